@@ -0,0 +1,315 @@
+//! Following the active chain block by block, with reorg handling.
+
+use std::collections::VecDeque;
+use std::thread;
+use std::time::Duration;
+
+use crate::bitcoin::BlockHash;
+use crate::json::Block;
+use crate::{Error, Result, RpcApi};
+
+const DEFAULT_MAX_REORG_DEPTH: usize = 100;
+
+#[derive(Clone, Debug)]
+pub enum ChainEvent {
+    /// The block was added on top of the followed chain.
+    Connected(Block),
+    /// The block, the tip of the followed chain, is no longer in the active chain. Blocks are
+    /// disconnected from the tip down, before the blocks of the new chain are connected.
+    Disconnected(Block),
+}
+
+/// How far the follower goes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopAt {
+    Tip,
+    /// This many blocks behind the tip.
+    Depth(u32),
+    /// The last notarized block, which can't be reorganized anymore.
+    LastNotarized,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Start {
+    /// The first block to connect.
+    Height(u64),
+    /// The first block to connect.
+    Hash(BlockHash),
+    /// The last block that was processed before, which is not connected again.
+    After(BlockHash),
+}
+
+/// Walks the chain forward and emits a [`ChainEvent`] per connected or disconnected block.
+///
+/// A reorg is noticed when the next block doesn't build on the followed tip, or when the tip
+/// left the active chain. The follower then disconnects blocks until it is back on the active
+/// chain. It keeps the last blocks up to the maximum reorg depth (100 by default); past them,
+/// and for a start block that was reorganized while nothing followed the chain, it walks back
+/// through the parents of the disconnected blocks.
+pub struct ChainFollower<'a, C: RpcApi> {
+    client: &'a C,
+    start: Option<Start>,
+    stop_at: StopAt,
+    max_reorg_depth: usize,
+    // the followed chain, tip last
+    chain: VecDeque<Block>,
+}
+
+impl<'a, C: RpcApi> ChainFollower<'a, C> {
+    pub fn new(client: &'a C, start: Start) -> Self {
+        ChainFollower {
+            client,
+            start: Some(start),
+            stop_at: StopAt::Tip,
+            max_reorg_depth: DEFAULT_MAX_REORG_DEPTH,
+            chain: VecDeque::new(),
+        }
+    }
+
+    pub fn stop_at(mut self, stop_at: StopAt) -> Self {
+        self.stop_at = stop_at;
+        self
+    }
+
+    pub fn max_reorg_depth(mut self, depth: usize) -> Self {
+        self.max_reorg_depth = depth.max(1);
+        self
+    }
+
+    /// The last connected block.
+    pub fn tip(&self) -> Option<&Block> {
+        self.chain.back()
+    }
+
+    /// The next event, or `None` when the follower caught up with the height it stops at.
+    pub fn next_event(&mut self) -> Result<Option<ChainEvent>> {
+        if let Some(start) = self.start.take() {
+            let block = match start {
+                Start::Height(height) => {
+                    let hash = self.client.get_block_hash(height)?;
+                    self.client.get_block(&hash)?
+                }
+                Start::Hash(hash) => self.client.get_block(&hash)?,
+                Start::After(hash) => {
+                    self.push(self.client.get_block(&hash)?);
+                    return self.next_event();
+                }
+            };
+            self.push(block.clone());
+            return Ok(Some(ChainEvent::Connected(block)));
+        }
+
+        let (tip_hash, tip_height) = match self.chain.back() {
+            Some(tip) => (tip.hash, tip.height as u64),
+            None => {
+                return Err(Error::KMDError(String::from(
+                    "the genesis block left the active chain",
+                )))
+            }
+        };
+
+        let count = self.client.get_block_count()? as u64;
+        if tip_height > count || self.client.get_block_hash(tip_height)? != tip_hash {
+            return Ok(self.pop()?.map(ChainEvent::Disconnected));
+        }
+
+        if tip_height >= self.stop_height(count)? {
+            return Ok(None);
+        }
+
+        let hash = self.client.get_block_hash(tip_height + 1)?;
+        let block = self.client.get_block(&hash)?;
+        if block.previous_blockhash != Some(tip_hash) {
+            // the chain changed in between the calls
+            return Ok(self.pop()?.map(ChainEvent::Disconnected));
+        }
+
+        self.push(block.clone());
+        Ok(Some(ChainEvent::Connected(block)))
+    }
+
+    /// Emits events to `handler` until it returns `false`, waiting `poll_interval` whenever the
+    /// follower caught up.
+    pub fn run<F>(&mut self, poll_interval: Duration, mut handler: F) -> Result<()>
+    where
+        F: FnMut(&ChainEvent) -> bool,
+    {
+        loop {
+            match self.next_event()? {
+                Some(event) => {
+                    if !handler(&event) {
+                        return Ok(());
+                    }
+                }
+                None => thread::sleep(poll_interval),
+            }
+        }
+    }
+
+    fn stop_height(&self, count: u64) -> Result<u64> {
+        Ok(match self.stop_at {
+            StopAt::Tip => count,
+            StopAt::Depth(depth) => count.saturating_sub(depth as u64),
+            StopAt::LastNotarized => self.client.get_notarization_info()?.notarized as u64,
+        })
+    }
+
+    fn push(&mut self, block: Block) {
+        self.chain.push_back(block);
+        if self.chain.len() > self.max_reorg_depth {
+            self.chain.pop_front();
+        }
+    }
+
+    // Refills the chain with the parent of the last kept block, which is looked up by hash
+    // because it may have left the active chain as well.
+    fn pop(&mut self) -> Result<Option<Block>> {
+        let block = self.chain.pop_back();
+        if self.chain.is_empty() {
+            if let Some(parent) = block.as_ref().and_then(|b| b.previous_blockhash) {
+                self.chain.push_back(self.client.get_block(&parent)?);
+            }
+        }
+        Ok(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::mock::{unexpected, MockChain, MockRpc};
+
+    fn client(chain: &Rc<RefCell<MockChain>>) -> MockRpc {
+        let chain = Rc::clone(chain);
+        MockRpc::new(move |cmd, args| {
+            chain
+                .borrow()
+                .handle(cmd, args)
+                .unwrap_or_else(|| unexpected(cmd))
+        })
+    }
+
+    // The events until the follower caught up, as `+hash` and `-hash`.
+    fn events<C: RpcApi>(follower: &mut ChainFollower<C>) -> Vec<(char, BlockHash)> {
+        let mut events = vec![];
+        while let Some(event) = follower.next_event().unwrap() {
+            events.push(match event {
+                ChainEvent::Connected(block) => ('+', block.hash),
+                ChainEvent::Disconnected(block) => ('-', block.hash),
+            });
+        }
+        events
+    }
+
+    #[test]
+    fn connects_blocks() {
+        let chain = Rc::new(RefCell::new(MockChain::new(4)));
+        let client = client(&chain);
+
+        let mut follower = ChainFollower::new(&client, Start::Height(1));
+        let expected: Vec<_> = (1..4).map(|h| ('+', chain.borrow().hash(h))).collect();
+        assert_eq!(events(&mut follower), expected);
+        assert_eq!(follower.tip().unwrap().hash, chain.borrow().tip());
+
+        let hash = chain.borrow_mut().push(&[]);
+        assert_eq!(events(&mut follower), vec![('+', hash)]);
+
+        let mut follower = ChainFollower::new(&client, Start::After(chain.borrow().hash(3)));
+        assert_eq!(events(&mut follower), vec![('+', hash)]);
+
+        let mut follower = ChainFollower::new(&client, Start::Height(0)).stop_at(StopAt::Depth(2));
+        assert_eq!(events(&mut follower).len(), 3);
+    }
+
+    #[test]
+    fn disconnects_reorganized_blocks() {
+        let chain = Rc::new(RefCell::new(MockChain::new(5)));
+        let client = client(&chain);
+        let mut follower = ChainFollower::new(&client, Start::Height(0));
+        events(&mut follower);
+
+        let old: Vec<_> = (3..5).map(|h| chain.borrow().hash(h)).collect();
+        chain.borrow_mut().disconnect(2);
+        let new: Vec<_> = (0..3).map(|_| chain.borrow_mut().push(&[])).collect();
+
+        assert_eq!(
+            events(&mut follower),
+            vec![
+                ('-', old[1]),
+                ('-', old[0]),
+                ('+', new[0]),
+                ('+', new[1]),
+                ('+', new[2]),
+            ]
+        );
+    }
+
+    #[test]
+    fn walks_back_past_the_kept_blocks() {
+        let chain = Rc::new(RefCell::new(MockChain::new(5)));
+        let client = client(&chain);
+
+        // the start block was reorganized while nothing followed the chain
+        let start = chain.borrow().tip();
+        let parent = chain.borrow().hash(3);
+        chain.borrow_mut().disconnect(2);
+        let new = chain.borrow_mut().push(&[]);
+
+        let mut follower = ChainFollower::new(&client, Start::After(start));
+        assert_eq!(
+            events(&mut follower),
+            vec![('-', start), ('-', parent), ('+', new)]
+        );
+
+        // deeper than the blocks the follower keeps
+        let mut follower = ChainFollower::new(&client, Start::Height(0)).max_reorg_depth(1);
+        events(&mut follower);
+        chain.borrow_mut().disconnect(3);
+        let new: Vec<_> = (0..4).map(|_| chain.borrow_mut().push(&[])).collect();
+
+        let events = events(&mut follower);
+        assert_eq!(events.iter().filter(|(e, _)| *e == '-').count(), 3);
+        assert_eq!(
+            &events[3..],
+            &new.iter().map(|h| ('+', *h)).collect::<Vec<_>>()[..]
+        );
+    }
+
+    #[test]
+    fn chain_changes_while_walking() {
+        let chain = Rc::new(RefCell::new(MockChain::new(3)));
+        let reorg_at = Rc::new(Cell::new(None));
+        let client = {
+            let chain = Rc::clone(&chain);
+            let reorg_at = Rc::clone(&reorg_at);
+            MockRpc::new(move |cmd, args| {
+                // the tip is replaced right after the follower checked it
+                if cmd == "getblockhash" && args[0].as_u64() == reorg_at.get() {
+                    reorg_at.set(None);
+                    let mut chain = chain.borrow_mut();
+                    chain.disconnect(2);
+                    chain.push(&[]);
+                    chain.push(&[]);
+                }
+                chain
+                    .borrow()
+                    .handle(cmd, args)
+                    .unwrap_or_else(|| unexpected(cmd))
+            })
+        };
+        let mut follower = ChainFollower::new(&client, Start::Height(0));
+        events(&mut follower);
+
+        let old = chain.borrow().tip();
+        chain.borrow_mut().push(&[]);
+        reorg_at.set(Some(3));
+
+        let events = events(&mut follower);
+        let mut expected = vec![('-', old)];
+        expected.extend((2..4).map(|h| ('+', chain.borrow().hash(h))));
+        assert_eq!(events, expected);
+    }
+}
@@ -3,15 +3,20 @@ use jsonrpc;
 use std::collections::HashMap;
 use std::io::ErrorKind;
 use std::iter::FromIterator;
+use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{fs, io, result};
 
 use os_info::Type as OSType;
 
 use crate::bitcoin::BlockHash;
+use crate::encryption::Passphrase;
+use crate::finality::{Finality, FinalityOutcome, FinalityPolicy};
 use crate::json::komodo::util::address::AddressType;
-use crate::json::komodo::util::amount::Amount;
+use crate::json::komodo::util::amount::{Amount, SignedAmount};
 use crate::json::*;
+use crate::operation::Operation;
 use crate::{bitcoin, json};
 // use crate::bitcoin::OutPoint;
 
@@ -125,6 +130,16 @@ fn handle_defaults<'a, 'b>(
     }
 }
 
+/// KMD rewards are only paid out when the spending transaction sets nLockTime to a recent time.
+/// 777 seconds in the past is what the other Komodo wallets use.
+pub(crate) fn rewards_locktime() -> Result<i64> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| Error::KMDError(String::from("system time is before UNIX epoch")))?;
+
+    Ok(now.as_secs() as i64 - 777)
+}
+
 /// Let the system find a local installation, or supply your own connection details.
 #[derive(Clone, Debug)]
 pub enum Auth {
@@ -284,6 +299,45 @@ pub trait RpcApi: Sized {
         // into_json()
     }
 
+    /// Requires `-addressindex`.
+    fn get_address_balance(&self, addresses: &[Address]) -> Result<AddressBalance> {
+        let query = AddressIndexQuery::new(addresses.to_vec());
+        self.call("getaddressbalance", &[into_json(query)?])
+    }
+
+    /// Requires `-addressindex`.
+    fn get_address_deltas(&self, query: &AddressIndexQuery) -> Result<Vec<AddressDelta>> {
+        self.call("getaddressdeltas", &[into_json(query)?])
+    }
+
+    /// Requires `-addressindex`.
+    fn get_address_mempool(&self, addresses: &[Address]) -> Result<Vec<AddressMempoolEntry>> {
+        let query = AddressIndexQuery::new(addresses.to_vec());
+        self.call("getaddressmempool", &[into_json(query)?])
+    }
+
+    /// Requires `-addressindex`.
+    fn get_address_txids(&self, query: &AddressIndexQuery) -> Result<Vec<bitcoin::Txid>> {
+        self.call("getaddresstxids", &[into_json(query)?])
+    }
+
+    /// Requires `-addressindex`.
+    fn get_address_utxos(&self, addresses: &[Address]) -> Result<Vec<AddressUtxo>> {
+        let query = AddressIndexQuery::new(addresses.to_vec());
+        self.call("getaddressutxos", &[into_json(query)?])
+    }
+
+    /// Same as [`get_address_utxos`](RpcApi::get_address_utxos), along with the tip the
+    /// utxos were read at.
+    fn get_address_utxos_with_chain_info(
+        &self,
+        addresses: &[Address],
+    ) -> Result<AddressUtxosWithChainInfo> {
+        let mut arg = into_json(AddressIndexQuery::new(addresses.to_vec()))?;
+        arg["chainInfo"] = true.into();
+        self.call("getaddressutxos", &[arg])
+    }
+
     fn get_blockchain_info(&self) -> Result<BlockchainInfo> {
         self.call("getblockchaininfo", &[])
     }
@@ -295,8 +349,39 @@ pub trait RpcApi: Sized {
         self.call("getblockhash", &[height.into()])
     }
 
-    fn get_blockhashes(&self) -> Result<()> {
-        unimplemented!()
+    /// Hashes of blocks with timestamps in `low..high`. Requires `-timestampindex`.
+    fn get_blockhashes(
+        &self,
+        high: u32,
+        low: u32,
+        no_orphans: bool,
+    ) -> Result<Vec<bitcoin::BlockHash>> {
+        let options = BlockHashesOptions {
+            no_orphans,
+            logical_times: false,
+        };
+        self.call(
+            "getblockhashes",
+            &[high.into(), low.into(), into_json(options)?],
+        )
+    }
+
+    /// Same as [`get_blockhashes`](RpcApi::get_blockhashes), along with each block's logical
+    /// timestamp.
+    fn get_blockhashes_with_logical_times(
+        &self,
+        high: u32,
+        low: u32,
+        no_orphans: bool,
+    ) -> Result<Vec<BlockHashLogicalTime>> {
+        let options = BlockHashesOptions {
+            no_orphans,
+            logical_times: true,
+        };
+        self.call(
+            "getblockhashes",
+            &[high.into(), low.into(), into_json(options)?],
+        )
     }
     fn get_blockheader_verbose(&self, hash: &bitcoin::BlockHash) -> Result<BlockHeader> {
         self.call("getblockheader", &[into_json(hash)?, into_json(true)?])
@@ -320,8 +405,9 @@ pub trait RpcApi: Sized {
     fn get_difficulty(&self) -> Result<f64> {
         self.call("getdifficulty", &[])
     }
-    fn get_last_segid_stakes(&self) -> Result<()> {
-        unimplemented!()
+    /// Staking chains only. `depth` is the number of blocks to look back from the tip.
+    fn get_last_segid_stakes(&self, depth: u32) -> Result<LastSegidStakes> {
+        self.call("getlastsegidstakes", &[depth.into()])
     }
     fn get_mempool_info(&self) -> Result<MempoolInfo> {
         self.call("getmempoolinfo", &[])
@@ -334,18 +420,10 @@ pub trait RpcApi: Sized {
         self.call("getrawmempool", &[into_json(true)?])
     }
 
+    /// Requires `-spentindex`.
     fn get_spent_info(&self, txid: bitcoin::Txid, index: u32) -> Result<SpentInfoResult> {
-        // let mut hashmap: HashMap<String, String> = HashMap::new();
-        // hashmap.insert(String::from("txid"), txid.to_string());
-        // hashmap.insert(String::from("index"), index.to_string());
-
-        // let args = [into_json(hashmap)?];
-
-        // self.call("getspentinfo", &args)
-
-        // TODO the getspentinfo call does not work
-
-        unimplemented!()
+        let arg = serde_json::json!({ "txid": txid, "index": index });
+        self.call("getspentinfo", &[arg])
     }
     fn get_txout(
         &self,
@@ -375,18 +453,116 @@ pub trait RpcApi: Sized {
     fn get_txout_set_info(&self) -> Result<TxOutSetInfoResult> {
         self.call("gettxoutsetinfo", &[])
     }
-    fn kvsearch(&self) -> Result<()> {
-        unimplemented!()
+    /// Returns `None` if the key was never set or has expired. KV-enabled chains only.
+    fn kvsearch(&self, key: &str) -> Result<Option<KvRecord>> {
+        let res: serde_json::Value = self.call("kvsearch", &[key.into()])?;
+        match res.get("error").and_then(|e| e.as_str()) {
+            Some("cant find key") => Ok(None),
+            Some(e) => Err(Error::KMDError(e.to_string())),
+            None => Ok(Some(serde_json::from_value(res)?)),
+        }
     }
-    fn kvupdate(&self) -> Result<()> {
-        unimplemented!()
+
+    /// Stores `value` under `key` for `days` (at most 365). With a passphrase, the key is
+    /// protected and can only be updated again with the same passphrase.
+    ///
+    /// Use [`kv::estimate_fee`](crate::kv::estimate_fee) to find the fee beforehand.
+    fn kvupdate(
+        &self,
+        key: &str,
+        value: &str,
+        days: u32,
+        passphrase: Option<&Passphrase>,
+    ) -> Result<KvUpdateResult> {
+        crate::kv::validate(key, days)?;
+
+        let mut args = vec![key.into(), value.into(), days.to_string().into()];
+        if let Some(passphrase) = passphrase {
+            args.push(passphrase.expose().into());
+        }
+
+        let res: serde_json::Value = self.call("kvupdate", &args)?;
+        match res.get("error").and_then(|e| e.as_str()) {
+            Some(e) => Err(Error::KMDError(e.to_string())),
+            None => Ok(serde_json::from_value(res)?),
+        }
     }
     fn miner_ids(&self, height: u64) -> Result<MinerIds> {
         self.call("minerids", &[into_json(height.to_string())?])
     }
-    fn notaries(&self, height: u64) -> Result<Notaries> {
-        self.call("notaries", &[into_json(height.to_string())?])
+    /// The notaries at `height`. Asset chains switch seasons by time, so pass the block time
+    /// to get the notaries of past blocks; the daemon uses the current time otherwise.
+    fn notaries(&self, height: u64, timestamp: Option<u64>) -> Result<Notaries> {
+        let mut args = [
+            into_json(height.to_string())?,
+            opt_into_json(timestamp.map(|t| t.to_string()))?,
+        ];
+        self.call("notaries", handle_defaults(&mut args, &[null()]))
+    }
+    /// Whether the transaction is in a block that has been notarized.
+    fn tx_notarized_confirmed(&self, txid: &bitcoin::Txid) -> Result<bool> {
+        let res: TxNotarizedConfirmedResult =
+            self.call("txnotarizedconfirmed", &[into_json(txid)?])?;
+        Ok(res.result)
+    }
+
+    /// The notarization state of the chain, as reported by `getinfo`.
+    fn get_notarization_info(&self) -> Result<NotarizationInfo> {
+        self.call("getinfo", &[])
+    }
+
+    /// Notarization transactions included in the block at `height`.
+    fn get_notarisations_for_block(&self, height: u64) -> Result<BlockNotarizations> {
+        self.call(
+            "getNotarisationsForBlock",
+            &[into_json(height.to_string())?],
+        )
+    }
+
+    /// Searches backwards from `height` for the latest notarization of `symbol`, looking at most
+    /// `limit` blocks deep (defaults to 1440). Returns `None` if none was found.
+    fn scan_notarisations_db(
+        &self,
+        height: u64,
+        symbol: &str,
+        limit: Option<u32>,
+    ) -> Result<Option<ScanNotarizationsResult>> {
+        let mut args = [
+            into_json(height.to_string())?,
+            into_json(symbol)?,
+            opt_into_json(limit.map(|l| l.to_string()))?,
+        ];
+        self.call("scanNotarisationsDB", handle_defaults(&mut args, &[null()]))
+    }
+
+    /// The MoM of the notarization covering `height`, or `None` if it isn't notarized yet.
+    /// A `height` of 0 uses the tip.
+    fn height_mom(&self, height: u64) -> Result<Option<HeightMoM>> {
+        let res: serde_json::Value = self.call("height_MoM", &[into_json(height.to_string())?])?;
+        match res.get("error").and_then(|e| e.as_str()) {
+            Some("no MoM for height") => Ok(None),
+            Some(e) => Err(Error::KMDError(e.to_string())),
+            None => Ok(Some(serde_json::from_value(res)?)),
+        }
+    }
+
+    /// Calculates the MoM over the `depth` blocks ending at `height`.
+    fn calc_mom(&self, height: u64, depth: u32) -> Result<CalcMoMResult> {
+        if depth == 0 || depth as u64 >= height {
+            return Err(Error::KMDError(String::from(
+                "MoM depth needs to be between 0 and height",
+            )));
+        }
+
+        self.call(
+            "calc_MoM",
+            &[
+                into_json(height.to_string())?,
+                into_json(depth.to_string())?,
+            ],
+        )
     }
+
     fn verify_chain(&self, checklevel: Option<u8>, numblocks: Option<u32>) -> Result<bool> {
         let mut args = [opt_into_json(checklevel)?, opt_into_json(numblocks)?];
 
@@ -398,6 +574,79 @@ pub trait RpcApi: Sized {
         self.call("verifytxoutproof", &[into_json(proof)?])
     }
 
+    fn generate(&self, numblocks: u32) -> Result<Vec<bitcoin::BlockHash>> {
+        self.call("generate", &[numblocks.into()])
+    }
+
+    fn get_generate(&self) -> Result<bool> {
+        self.call("getgenerate", &[])
+    }
+
+    /// Komodo stakes instead of mines when `generate` is true and `genproclimit` is 0.
+    fn set_generate(&self, generate: bool, genproclimit: Option<i32>) -> Result<()> {
+        let mut args = [generate.into(), opt_into_json(genproclimit)?];
+        self.call("setgenerate", handle_defaults(&mut args, &[null()]))
+    }
+
+    fn get_block_subsidy(&self, height: Option<u64>) -> Result<BlockSubsidy> {
+        let mut args = [opt_into_json(height)?];
+        self.call("getblocksubsidy", handle_defaults(&mut args, &[null()]))
+    }
+
+    fn get_block_template(&self, request: Option<&BlockTemplateRequest>) -> Result<BlockTemplate> {
+        let mut args = [opt_into_json(request)?];
+        self.call("getblocktemplate", handle_defaults(&mut args, &[null()]))
+    }
+
+    fn get_local_sol_ps(&self) -> Result<f64> {
+        self.call("getlocalsolps", &[])
+    }
+
+    fn get_mining_info(&self) -> Result<MiningInfo> {
+        self.call("getmininginfo", &[])
+    }
+
+    /// `blocks` defaults to 120, -1 means since the last difficulty change. `height` defaults
+    /// to the current height.
+    fn get_network_hash_ps(&self, blocks: Option<i32>, height: Option<i32>) -> Result<u64> {
+        let mut args = [opt_into_json(blocks)?, opt_into_json(height)?];
+        self.call(
+            "getnetworkhashps",
+            handle_defaults(&mut args, &[120.into(), null()]),
+        )
+    }
+
+    fn get_network_sol_ps(&self, blocks: Option<i32>, height: Option<i32>) -> Result<u64> {
+        let mut args = [opt_into_json(blocks)?, opt_into_json(height)?];
+        self.call(
+            "getnetworksolps",
+            handle_defaults(&mut args, &[120.into(), null()]),
+        )
+    }
+
+    /// `fee_delta` is added to the fee of the transaction when it is considered for a block,
+    /// it is not actually paid.
+    fn prioritise_transaction(
+        &self,
+        txid: &bitcoin::Txid,
+        priority_delta: f64,
+        fee_delta: SignedAmount,
+    ) -> Result<bool> {
+        self.call(
+            "prioritisetransaction",
+            &[
+                into_json(txid)?,
+                priority_delta.into(),
+                fee_delta.as_sat().into(),
+            ],
+        )
+    }
+
+    /// Returns `None` when the block was accepted, or the reason it was rejected otherwise.
+    fn submit_block(&self, hex: &str) -> Result<Option<String>> {
+        self.call("submitblock", &[hex.into()])
+    }
+
     fn createrawtransaction(
         &self,
         inputs: &[json::CreateRawTransactionInput],
@@ -422,8 +671,8 @@ pub trait RpcApi: Sized {
             handle_defaults(&mut args, &defaults),
         )
     }
-    fn decoderawtransaction(&self) -> Result<()> {
-        unimplemented!()
+    fn decoderawtransaction(&self, hex: &str) -> Result<json::DecodeRawTransactionResult> {
+        self.call("decoderawtransaction", &[hex.into()])
     }
     fn decodescript(&self) -> Result<()> {
         unimplemented!()
@@ -434,11 +683,37 @@ pub trait RpcApi: Sized {
     fn getrawtransaction(&self) -> Result<()> {
         unimplemented!()
     }
-    fn sendrawtransaction(&self) -> Result<()> {
-        unimplemented!()
+    fn sendrawtransaction(
+        &self,
+        hex: &str,
+        allow_high_fees: Option<bool>,
+    ) -> Result<bitcoin::Txid> {
+        let mut args = [hex.into(), opt_into_json(allow_high_fees)?];
+        self.call("sendrawtransaction", handle_defaults(&mut args, &[null()]))
     }
-    fn signrawtransaction(&self) -> Result<()> {
-        unimplemented!()
+
+    /// Leaving `privkeys` empty signs with the keys in the wallet.
+    fn signrawtransaction(
+        &self,
+        hex: &str,
+        prevtxs: Option<&[json::SignRawTransactionInput]>,
+        privkeys: Option<&[PrivateKey]>,
+        sighash_type: Option<json::SigHashType>,
+    ) -> Result<json::SignRawTransactionResult> {
+        let privkeys: Option<Vec<String>> =
+            privkeys.map(|keys| keys.iter().map(|key| key.to_string()).collect());
+        let mut args = vec![
+            hex.into(),
+            opt_into_json(prevtxs)?,
+            opt_into_json(privkeys)?,
+            opt_into_json(sighash_type)?,
+        ];
+        // handle_defaults can't be used here: an empty privkeys array makes the daemon ignore
+        // the wallet keys, so a null has to be passed on when only the sighash type is given.
+        while args.last() == Some(&null()) {
+            args.pop();
+        }
+        self.call("signrawtransaction", &args)
     }
 
     fn get_raw_transaction_verbose(
@@ -452,10 +727,89 @@ pub trait RpcApi: Sized {
         self.call("getrawtransaction", &[into_json(txid)?, 0.into()])
     }
 
+    fn create_multisig(
+        &self,
+        n_required: u8,
+        keys: &[json::PubkeyOrAddress],
+    ) -> Result<json::CreateMultiSigResult> {
+        if n_required > 15 {
+            return Err(Error::KMDError(String::from(
+                "No more than 15 signers in a msig allowed",
+            )));
+        }
+
+        self.call("createmultisig", &[n_required.into(), into_json(keys)?])
+    }
+
     fn ping(&self) -> Result<()> {
         self.call("ping", &[])
     }
 
+    fn add_node(&self, node: &SocketAddr, command: AddNodeCommand) -> Result<()> {
+        self.call("addnode", &[node.to_string().into(), into_json(command)?])
+    }
+
+    fn clear_banned(&self) -> Result<()> {
+        self.call("clearbanned", &[])
+    }
+
+    fn disconnect_node(&self, node: &SocketAddr) -> Result<()> {
+        self.call("disconnectnode", &[node.to_string().into()])
+    }
+
+    /// Without `node`, all added nodes are returned. With `dns`, the result includes whether
+    /// the nodes are connected.
+    fn get_added_node_info(
+        &self,
+        dns: bool,
+        node: Option<&SocketAddr>,
+    ) -> Result<Vec<AddedNodeInfo>> {
+        let mut args = [dns.into(), opt_into_json(node.map(|n| n.to_string()))?];
+        self.call("getaddednodeinfo", handle_defaults(&mut args, &[null()]))
+    }
+
+    fn get_connection_count(&self) -> Result<u32> {
+        self.call("getconnectioncount", &[])
+    }
+
+    fn get_deprecation_info(&self) -> Result<DeprecationInfo> {
+        self.call("getdeprecationinfo", &[])
+    }
+
+    fn get_net_totals(&self) -> Result<NetTotals> {
+        self.call("getnettotals", &[])
+    }
+
+    fn get_network_info(&self) -> Result<NetworkInfo> {
+        self.call("getnetworkinfo", &[])
+    }
+
+    fn get_peer_info(&self) -> Result<Vec<PeerInfo>> {
+        self.call("getpeerinfo", &[])
+    }
+
+    fn list_banned(&self) -> Result<Vec<BannedSubNet>> {
+        self.call("listbanned", &[])
+    }
+
+    /// `bantime` is in seconds, or a UNIX timestamp when `absolute` is true. Defaults to 24
+    /// hours (`-bantime`).
+    fn set_ban(
+        &self,
+        subnet: &SubNet,
+        command: SetBanCommand,
+        bantime: Option<u64>,
+        absolute: Option<bool>,
+    ) -> Result<()> {
+        let mut args = [
+            into_json(subnet)?,
+            into_json(command)?,
+            opt_into_json(bantime)?,
+            opt_into_json(absolute)?,
+        ];
+        self.call("setban", handle_defaults(&mut args, &[0.into(), null()]))
+    }
+
     // Label is deprecated and thus not used in the method call.
     // Todo keys are either an address or a pubkey.
     fn add_multi_sig_address(
@@ -478,6 +832,28 @@ pub trait RpcApi: Sized {
             .map(|path: String| PathBuf::from(&path))
     }
 
+    /// The daemon writes the dump to its `-exportdir`, `filename` can't contain a path.
+    /// Returns the full path of the dump, which can be read with
+    /// [crate::wallet_dump::WalletDump::from_file].
+    fn dump_wallet(&self, filename: &str) -> Result<PathBuf> {
+        self.call("dumpwallet", &[filename.into()])
+            .map(|path: String| PathBuf::from(&path))
+    }
+
+    fn import_wallet(&self, path: &str) -> Result<()> {
+        self.call("importwallet", &[path.into()])
+    }
+
+    /// Like `dump_wallet`, but includes the shielded keys.
+    fn z_export_wallet(&self, filename: &str) -> Result<PathBuf> {
+        self.call("z_exportwallet", &[filename.into()])
+            .map(|path: String| PathBuf::from(&path))
+    }
+
+    fn z_import_wallet(&self, path: &str) -> Result<()> {
+        self.call("z_importwallet", &[path.into()])
+    }
+
     fn clean_wallet_transactions(&self) -> Result<json::CleanedWalletTransactions> {
         self.call("cleanwallettransactions", &[])
     }
@@ -490,7 +866,7 @@ pub trait RpcApi: Sized {
         match address.addr_type {
             AddressType::Shielded => {
                 return Err(Error::KMDError(String::from(
-                    "no support for shielded addresses for this call",
+                    "no support for shielded addresses for this call, use z_export_key",
                 )))
             }
             _ => {}
@@ -498,6 +874,32 @@ pub trait RpcApi: Sized {
         self.call("dumpprivkey", &[address.to_string().into()])
     }
 
+    /// Only works when komodod runs with `-experimentalfeatures -developerencryptwallet`.
+    /// The daemon shuts down after the wallet is encrypted.
+    fn encrypt_wallet(&self, passphrase: &Passphrase) -> Result<String> {
+        self.call("encryptwallet", &[passphrase.expose().into()])
+    }
+
+    fn wallet_lock(&self) -> Result<()> {
+        self.call("walletlock", &[])
+    }
+
+    /// Unlocks the wallet for `timeout` seconds. See [crate::encryption::UnlockedWallet] to
+    /// lock it again as soon as it's no longer needed.
+    fn wallet_passphrase(&self, passphrase: &Passphrase, timeout: u64) -> Result<()> {
+        self.call(
+            "walletpassphrase",
+            &[passphrase.expose().into(), timeout.into()],
+        )
+    }
+
+    fn wallet_passphrase_change(&self, old: &Passphrase, new: &Passphrase) -> Result<()> {
+        self.call(
+            "walletpassphrasechange",
+            &[old.expose().into(), new.expose().into()],
+        )
+    }
+
     fn get_balance(
         &self,
         minconf: Option<usize>,
@@ -756,11 +1158,318 @@ pub trait RpcApi: Sized {
         self.call("settxfee", &[amount.into()])
     }
 
+    fn z_get_new_address(&self, address_type: Option<ZAddressType>) -> Result<Address> {
+        let mut args = [opt_into_json(address_type)?];
+        self.call("z_getnewaddress", handle_defaults(&mut args, &[null()]))
+    }
+
+    fn z_list_addresses(&self, include_watch_only: Option<bool>) -> Result<Vec<Address>> {
+        let mut args = [opt_into_json(include_watch_only)?];
+        self.call("z_listaddresses", handle_defaults(&mut args, &[null()]))
+    }
+
+    fn z_get_balance(&self, address: &Address, minconf: Option<usize>) -> Result<Amount> {
+        let mut args = [address.to_string().into(), opt_into_json(minconf)?];
+        Ok(Amount::from_kmd(self.call(
+            "z_getbalance",
+            handle_defaults(&mut args, &[null()]),
+        )?)?)
+    }
+
+    fn z_get_total_balance(
+        &self,
+        minconf: Option<usize>,
+        include_watch_only: Option<bool>,
+    ) -> Result<ZTotalBalance> {
+        let mut args = [opt_into_json(minconf)?, opt_into_json(include_watch_only)?];
+        self.call(
+            "z_gettotalbalance",
+            handle_defaults(&mut args, &[1.into(), null()]),
+        )
+    }
+
+    fn z_list_unspent(
+        &self,
+        minconf: Option<usize>,
+        maxconf: Option<usize>,
+        include_watch_only: Option<bool>,
+        addresses: Option<&[&Address]>,
+    ) -> Result<Vec<ZListUnspentResult>> {
+        let addresses: Option<Vec<String>> =
+            addresses.map(|addresses| addresses.iter().map(|a| a.to_string()).collect());
+        let mut args = [
+            opt_into_json(minconf)?,
+            opt_into_json(maxconf)?,
+            opt_into_json(include_watch_only)?,
+            opt_into_json(addresses)?,
+        ];
+        let defaults = [
+            into_json(1)?,
+            into_json(9999999)?,
+            into_json(false)?,
+            empty_arr(),
+        ];
+        self.call("z_listunspent", handle_defaults(&mut args, &defaults))
+    }
+
+    fn z_list_received_by_address(
+        &self,
+        address: &Address,
+        minconf: Option<usize>,
+    ) -> Result<Vec<ZReceivedByAddress>> {
+        let mut args = [address.to_string().into(), opt_into_json(minconf)?];
+        self.call(
+            "z_listreceivedbyaddress",
+            handle_defaults(&mut args, &[null()]),
+        )
+    }
+
+    fn z_view_transaction(&self, txid: &bitcoin::Txid) -> Result<ZViewTransaction> {
+        self.call("z_viewtransaction", &[into_json(txid)?])
+    }
+
+    fn z_export_key(&self, address: &Address) -> Result<String> {
+        self.call("z_exportkey", &[address.to_string().into()])
+    }
+
+    fn z_import_key(
+        &self,
+        key: &str,
+        rescan: Option<RescanPolicy>,
+        start_height: Option<u64>,
+    ) -> Result<Option<ZImportKeyResult>> {
+        let mut args = [
+            key.into(),
+            opt_into_json(rescan)?,
+            opt_into_json(start_height)?,
+        ];
+        self.call(
+            "z_importkey",
+            handle_defaults(&mut args, &[into_json(RescanPolicy::WhenKeyIsNew)?, null()]),
+        )
+    }
+
+    fn z_export_viewing_key(&self, address: &Address) -> Result<String> {
+        self.call("z_exportviewingkey", &[address.to_string().into()])
+    }
+
+    fn z_import_viewing_key(
+        &self,
+        viewing_key: &str,
+        rescan: Option<RescanPolicy>,
+        start_height: Option<u64>,
+    ) -> Result<Option<ZImportKeyResult>> {
+        let mut args = [
+            viewing_key.into(),
+            opt_into_json(rescan)?,
+            opt_into_json(start_height)?,
+        ];
+        self.call(
+            "z_importviewingkey",
+            handle_defaults(&mut args, &[into_json(RescanPolicy::WhenKeyIsNew)?, null()]),
+        )
+    }
+
+    fn z_send_many(
+        &self,
+        from: &Address,
+        recipients: &[ZSendManyRecipient],
+        minconf: Option<u32>,
+        fee: Option<Amount>,
+    ) -> Result<OperationId> {
+        for memo in recipients.iter().filter_map(|r| r.memo.as_ref()) {
+            memo.validate()?;
+        }
+
+        let mut args = [
+            from.to_string().into(),
+            into_json(recipients)?,
+            opt_into_json(minconf)?,
+            opt_into_json(fee.map(|fee| fee.as_kmd()))?,
+        ];
+        self.call(
+            "z_sendmany",
+            handle_defaults(&mut args, &[1.into(), null()]),
+        )
+    }
+
+    /// `from` is a transparent address or `*` for all transparent addresses in the wallet.
+    fn z_shield_coinbase(
+        &self,
+        from: &str,
+        to: &Address,
+        fee: Option<Amount>,
+        limit: Option<u32>,
+    ) -> Result<ZShieldCoinbaseResult> {
+        let mut args = [
+            from.into(),
+            to.to_string().into(),
+            opt_into_json(fee.map(|fee| fee.as_kmd()))?,
+            opt_into_json(limit)?,
+        ];
+        self.call(
+            "z_shieldcoinbase",
+            handle_defaults(&mut args, &[into_json(0.0001)?, null()]),
+        )
+    }
+
+    /// `from` are addresses, or `ANY_TADDR`, `ANY_SPROUT` or `ANY_SAPLING`. Like the daemon,
+    /// `shielded_limit` defaults to 200 notes when Sapling notes are merged and to 20 otherwise.
+    fn z_merge_to_address(
+        &self,
+        from: &[&str],
+        to: &Address,
+        fee: Option<Amount>,
+        transparent_limit: Option<u32>,
+        shielded_limit: Option<u32>,
+        memo: Option<&Memo>,
+    ) -> Result<ZMergeToAddressResult> {
+        if let Some(memo) = memo {
+            memo.validate()?;
+        }
+
+        let mut args = [
+            into_json(from)?,
+            to.to_string().into(),
+            opt_into_json(fee.map(|fee| fee.as_kmd()))?,
+            opt_into_json(transparent_limit)?,
+            opt_into_json(shielded_limit)?,
+            opt_into_json(memo)?,
+        ];
+        // the daemon only applies its per pool default when no limit is passed
+        let sapling = to.to_string().starts_with("zs")
+            || from
+                .iter()
+                .any(|a| *a == "ANY_SAPLING" || a.starts_with("zs"));
+        let shielded_default = if sapling { 200 } else { 20 };
+        let defaults = [
+            into_json(0.0001)?,
+            into_json(50)?,
+            into_json(shielded_default)?,
+            null(),
+        ];
+        self.call("z_mergetoaddress", handle_defaults(&mut args, &defaults))
+    }
+
+    /// Without ids, the status of all operations is returned.
+    fn z_get_operation_status(&self, ids: Option<&[OperationId]>) -> Result<Vec<OperationStatus>> {
+        let mut args = [opt_into_json(ids)?];
+        self.call(
+            "z_getoperationstatus",
+            handle_defaults(&mut args, &[null()]),
+        )
+    }
+
+    /// Like `z_get_operation_status`, but only returns finished operations, which are removed
+    /// from the daemon.
+    fn z_get_operation_result(&self, ids: Option<&[OperationId]>) -> Result<Vec<OperationStatus>> {
+        let mut args = [opt_into_json(ids)?];
+        self.call(
+            "z_getoperationresult",
+            handle_defaults(&mut args, &[null()]),
+        )
+    }
+
+    fn z_list_operation_ids(&self, state: Option<OperationState>) -> Result<Vec<OperationId>> {
+        let mut args = [opt_into_json(state)?];
+        self.call("z_listoperationids", handle_defaults(&mut args, &[null()]))
+    }
+
+    fn operation(&self, id: OperationId) -> Operation<'_, Self> {
+        Operation::new(self, id)
+    }
+
+    /// Waits for an operation to finish and returns the txid it created, e.g.
+    /// `client.wait_for_operation(client.z_send_many(..)?, timeout)`.
+    fn wait_for_operation(&self, id: OperationId, timeout: Duration) -> Result<bitcoin::Txid> {
+        self.operation(id).clear_result(true).wait(timeout)
+    }
+
+    /// Waits until the transaction is final according to `policy`. See [`Finality`] to follow
+    /// the progress.
+    fn wait_for_finality(
+        &self,
+        txid: bitcoin::Txid,
+        policy: FinalityPolicy,
+        timeout: Duration,
+    ) -> Result<FinalityOutcome> {
+        Finality::new(self, txid, policy).wait(timeout, |_| {})
+    }
+
     fn get_snapshot(&self, top: Option<String>) -> Result<Snapshot> {
         let mut args = [opt_into_json(top)?];
         self.call("getsnapshot", handle_defaults(&mut args, &[null()]))
     }
 
+    /// Sends all UTXOs that have accrued KMD rewards back to `address`, which claims the
+    /// rewards. The transaction pays `fee` (defaults to 0.0001 KMD) from the claimed amount.
+    fn claim_rewards(&self, address: &Address, fee: Option<Amount>) -> Result<ClaimRewardsResult> {
+        let fee = fee.unwrap_or(Amount::from_sat(10_000));
+        let utxos: Vec<ListUnspentResult> = self
+            .list_unspent(Some(1), None, None)?
+            .into_iter()
+            .filter(|utxo| {
+                utxo.spendable && utxo.interest.map_or(false, |i| i > Amount::from_sat(0))
+            })
+            .collect();
+
+        if utxos.is_empty() {
+            return Err(Error::KMDError(String::from(
+                "no UTXOs with unclaimed rewards in wallet",
+            )));
+        }
+
+        let mut principal = Amount::from_sat(0);
+        let mut claimed = Amount::from_sat(0);
+        for utxo in &utxos {
+            principal += utxo.amount.to_unsigned()?;
+            claimed += utxo.interest.unwrap_or(Amount::from_sat(0));
+        }
+
+        if principal + claimed <= fee {
+            return Err(Error::KMDError(String::from(
+                "fee exceeds the value of the UTXOs to claim",
+            )));
+        }
+        let sent = principal + claimed - fee;
+
+        let locktime = rewards_locktime()?;
+
+        let inputs: Vec<CreateRawTransactionInput> = utxos
+            .iter()
+            .map(|utxo| CreateRawTransactionInput {
+                txid: utxo.txid,
+                vout: utxo.vout as u32,
+                sequence: None,
+            })
+            .collect();
+        let mut outputs = HashMap::new();
+        outputs.insert(address.to_string(), sent);
+
+        let unsigned = self.createrawtransaction(&inputs, &outputs, Some(locktime), None)?;
+        let signed = self.signrawtransaction(&unsigned, None, None, None)?;
+        if !signed.complete {
+            return Err(Error::KMDError(String::from(
+                "wallet could not sign all inputs of the claim transaction",
+            )));
+        }
+        let txid = self.sendrawtransaction(&signed.hex, None)?;
+
+        Ok(ClaimRewardsResult {
+            txid,
+            address: address.clone(),
+            inputs: inputs
+                .into_iter()
+                .map(|input| bitcoin::OutPoint::new(input.txid, input.vout))
+                .collect(),
+            locktime,
+            principal,
+            claimed,
+            fee,
+            sent,
+        })
+    }
+
     // TOKENS
 
     fn tokenv2info(&self, token_id: &str) -> Result<TokenInfo> {
@@ -798,4 +1507,39 @@ mod tests {
         let config_file = ConfigFile::new("PIRATE");
         println!("{:#?}", &config_file);
     }
+
+    #[test]
+    fn merge_uses_the_note_limit_of_the_pool() {
+        use crate::json::{Address, Memo};
+        use crate::mock::{unexpected, MockRpc};
+        use crate::RpcApi;
+        use serde_json::json;
+
+        let client = MockRpc::new(|cmd, _| match cmd {
+            "z_mergetoaddress" => Ok(json!({
+                "remainingUTXOs": 0,
+                "remainingTransparentValue": 0.0,
+                "remainingNotes": 0,
+                "remainingShieldedValue": 0.0,
+                "mergingUTXOs": 0,
+                "mergingTransparentValue": 0.0,
+                "mergingNotes": 2,
+                "mergingShieldedValue": 1.0,
+                "opid": "opid-1"
+            })),
+            _ => unexpected(cmd),
+        });
+        let to: Address =
+            serde_json::from_value(json!("RAqS1bAuWqW2f6ufsU5H4XpKfy5Pqj2oHz")).unwrap();
+        let memo = Memo::from_text("merged").unwrap();
+
+        for from in &["ANY_SAPLING", "ANY_SPROUT"] {
+            client
+                .z_merge_to_address(&[*from], &to, None, None, None, Some(&memo))
+                .unwrap();
+        }
+        let calls = client.calls("z_mergetoaddress");
+        assert_eq!(calls[0][4], json!(200));
+        assert_eq!(calls[1][4], json!(20));
+    }
 }
@@ -0,0 +1,584 @@
+//! Coin selection and UTXO locking on top of `list_unspent` and `lock_unspent`.
+//!
+//! A [CoinSelector] picks wallet UTXOs for a payment and locks them in the daemon, so that
+//! other payers can't select them until the transaction is broadcast or the lock times out.
+//!
+//! Selecting and locking are two calls. Payers sharing a selector never pick the same UTXOs,
+//! but another selector or another process using the same wallet can pick them in between the
+//! two calls; one of the transactions then fails to broadcast as a double spend.
+
+use std::cmp::Reverse;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::bitcoin::{OutPoint, Txid};
+use crate::json::komodo::util::amount::Amount;
+use crate::json::{CreateRawTransactionInput, ListUnspentResult};
+use crate::{Error, Result, RpcApi};
+
+/// Estimated size of a transparent v4 (Sapling) transaction without inputs and outputs.
+pub const TX_OVERHEAD_SIZE: usize = 30;
+/// Estimated size of a P2PKH input.
+pub const INPUT_SIZE: usize = 148;
+/// Estimated size of a P2PKH output.
+pub const OUTPUT_SIZE: usize = 34;
+/// The standard maximum size of a transaction that is relayed by komodod.
+pub const MAX_STANDARD_TX_SIZE: usize = 100_000;
+
+/// Change below this amount is added to the fee instead of creating an output.
+pub const DEFAULT_DUST_THRESHOLD: u64 = 546;
+/// Fee rate in satoshis per 1000 bytes.
+pub const DEFAULT_FEE_RATE: u64 = 10_000;
+
+// Branch-and-bound gives up after this many tries and falls back to largest-first.
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// The estimated size of a transaction with this many P2PKH inputs and outputs.
+pub fn estimate_size(inputs: usize, outputs: usize) -> usize {
+    TX_OVERHEAD_SIZE + inputs * INPUT_SIZE + outputs * OUTPUT_SIZE
+}
+
+/// The fee of a transaction with this many P2PKH inputs and outputs, with `fee_rate` in
+/// satoshis per 1000 bytes.
+pub fn estimate_fee(fee_rate: Amount, inputs: usize, outputs: usize) -> Amount {
+    let size = estimate_size(inputs, outputs) as u64;
+    Amount::from_sat((fee_rate.as_sat() * size + 999) / 1000)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Strategy {
+    /// Searches for a set of UTXOs that needs no change output, falls back to `LargestFirst`.
+    BranchAndBound,
+    LargestFirst,
+    OldestFirst,
+}
+
+/// How UTXOs that accrued KMD rewards are treated.
+///
+/// Rewards are only paid out when the spending transaction sets a recent nLockTime, so spending
+/// an interest-bearing UTXO in a payment without a locktime forfeits the rewards.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InterestPreference {
+    /// Select UTXOs regardless of their rewards, and don't count rewards as value.
+    Ignore,
+    /// Select interest-bearing UTXOs first and count their rewards as value. The transaction
+    /// must set a recent locktime.
+    Prefer,
+    /// Never select interest-bearing UTXOs.
+    Avoid,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Utxo {
+    pub outpoint: OutPoint,
+    pub amount: Amount,
+    pub interest: Amount,
+    pub confirmations: u32,
+}
+
+impl Utxo {
+    pub fn from_unspent(unspent: &ListUnspentResult) -> Result<Self> {
+        Ok(Utxo {
+            outpoint: OutPoint::new(unspent.txid, unspent.vout as u32),
+            amount: unspent.amount.to_unsigned()?,
+            interest: unspent.interest.unwrap_or(Amount::from_sat(0)),
+            confirmations: unspent.confirmations,
+        })
+    }
+
+    pub fn has_interest(&self) -> bool {
+        self.interest > Amount::from_sat(0)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Selection {
+    pub utxos: Vec<Utxo>,
+    /// The value of the selected UTXOs, including rewards when they are counted.
+    pub total: Amount,
+    pub fee: Amount,
+    /// Zero when the change was below the dust threshold and added to the fee.
+    pub change: Amount,
+}
+
+impl Selection {
+    pub fn outpoints(&self) -> Vec<OutPoint> {
+        self.utxos.iter().map(|utxo| utxo.outpoint).collect()
+    }
+
+    pub fn inputs(&self) -> Vec<CreateRawTransactionInput> {
+        self.utxos
+            .iter()
+            .map(|utxo| CreateRawTransactionInput {
+                txid: utxo.outpoint.txid,
+                vout: utxo.outpoint.vout,
+                sequence: None,
+            })
+            .collect()
+    }
+}
+
+pub struct CoinSelector {
+    pub strategy: Strategy,
+    pub interest: InterestPreference,
+    pub dust_threshold: Amount,
+    /// Satoshis per 1000 bytes.
+    pub fee_rate: Amount,
+    pub min_conf: usize,
+    /// How long selected UTXOs stay locked when the transaction is not broadcast.
+    pub lock_timeout: Duration,
+    // list_unspent and lock_unspent are two calls, payers sharing this selector must not
+    // select in between them.
+    selecting: Mutex<()>,
+    locks: Mutex<Vec<Arc<Lock>>>,
+}
+
+impl Default for CoinSelector {
+    fn default() -> Self {
+        CoinSelector {
+            strategy: Strategy::BranchAndBound,
+            interest: InterestPreference::Avoid,
+            dust_threshold: Amount::from_sat(DEFAULT_DUST_THRESHOLD),
+            fee_rate: Amount::from_sat(DEFAULT_FEE_RATE),
+            min_conf: 1,
+            lock_timeout: Duration::from_secs(60),
+            selecting: Mutex::new(()),
+            locks: Mutex::new(vec![]),
+        }
+    }
+}
+
+impl CoinSelector {
+    pub fn new(strategy: Strategy, interest: InterestPreference) -> Self {
+        CoinSelector {
+            strategy,
+            interest,
+            ..Default::default()
+        }
+    }
+
+    /// The estimated fee of a transaction with this many P2PKH inputs and outputs.
+    pub fn fee(&self, inputs: usize, outputs: usize) -> Amount {
+        estimate_fee(self.fee_rate, inputs, outputs)
+    }
+
+    fn value(&self, utxo: &Utxo) -> u64 {
+        match self.interest {
+            InterestPreference::Prefer => utxo.amount.as_sat() + utxo.interest.as_sat(),
+            _ => utxo.amount.as_sat(),
+        }
+    }
+
+    /// Selects UTXOs from `utxos` to pay `target` to `outputs` recipients, plus the fee.
+    pub fn select(&self, utxos: &[Utxo], target: Amount, outputs: usize) -> Result<Selection> {
+        let mut candidates: Vec<Utxo> = utxos
+            .iter()
+            .filter(|utxo| !(self.interest == InterestPreference::Avoid && utxo.has_interest()))
+            .cloned()
+            .collect();
+
+        match self.strategy {
+            Strategy::OldestFirst => candidates.sort_by_key(|utxo| Reverse(utxo.confirmations)),
+            _ => candidates.sort_by_key(|utxo| Reverse(self.value(utxo))),
+        }
+        if self.interest == InterestPreference::Prefer {
+            // stable sort, keeps the strategy's order within both groups
+            candidates.sort_by_key(|utxo| !utxo.has_interest());
+        }
+
+        if self.strategy == Strategy::BranchAndBound {
+            if let Some(selection) = self.branch_and_bound(&candidates, target, outputs) {
+                return Ok(selection);
+            }
+        }
+
+        self.accumulate(&candidates, target, outputs)
+    }
+
+    /// Takes UTXOs in the given order until the target and fee are covered.
+    fn accumulate(&self, utxos: &[Utxo], target: Amount, outputs: usize) -> Result<Selection> {
+        let target = target.as_sat();
+        let mut total = 0;
+
+        for (i, utxo) in utxos.iter().enumerate() {
+            total += self.value(utxo);
+            let inputs = i + 1;
+
+            let fee_with_change = self.fee(inputs, outputs + 1).as_sat();
+            if total >= target + fee_with_change {
+                let change = total - target - fee_with_change;
+                if change >= self.dust_threshold.as_sat() {
+                    return Ok(self.selection(&utxos[..inputs], total, fee_with_change, change));
+                }
+            }
+
+            let fee = self.fee(inputs, outputs).as_sat();
+            if total >= target + fee {
+                return Ok(self.selection(&utxos[..inputs], total, total - target, 0));
+            }
+        }
+
+        Err(Error::KMDError(format!(
+            "insufficient funds: {} available, {} needed",
+            Amount::from_sat(total),
+            Amount::from_sat(target)
+        )))
+    }
+
+    /// Depth-first search for a set of UTXOs whose value, minus the fee of spending them,
+    /// is between the target and the target plus the cost of a change output.
+    fn branch_and_bound(
+        &self,
+        utxos: &[Utxo],
+        target: Amount,
+        outputs: usize,
+    ) -> Option<Selection> {
+        let input_fee = self.fee(1, 0).as_sat() - self.fee(0, 0).as_sat();
+        let effective: Vec<(usize, u64)> = utxos
+            .iter()
+            .enumerate()
+            .filter(|(_, utxo)| self.value(utxo) > input_fee)
+            .map(|(i, utxo)| (i, self.value(utxo) - input_fee))
+            .collect();
+
+        let actual_target = target.as_sat() + self.fee(0, outputs).as_sat();
+        let cost_of_change = self.fee(0, 1).as_sat() + self.dust_threshold.as_sat();
+
+        let mut current: Vec<bool> = vec![];
+        let mut current_value = 0;
+        let mut available: u64 = effective.iter().map(|(_, value)| value).sum();
+        let mut best: Option<(Vec<bool>, u64)> = None;
+
+        for _ in 0..BNB_MAX_TRIES {
+            let mut backtrack = false;
+            if current_value + available < actual_target
+                || current_value > actual_target + cost_of_change
+            {
+                backtrack = true;
+            } else if current_value >= actual_target {
+                let waste = current_value - actual_target;
+                if best
+                    .as_ref()
+                    .map_or(true, |(_, best_waste)| waste < *best_waste)
+                {
+                    best = Some((current.clone(), waste));
+                }
+                backtrack = true;
+            }
+
+            if backtrack {
+                // walk back to the last included UTXO and try the branch without it
+                while current.last() == Some(&false) {
+                    current.pop();
+                    available += effective[current.len()].1;
+                }
+                if current.is_empty() {
+                    break;
+                }
+                *current.last_mut().unwrap() = false;
+                current_value -= effective[current.len() - 1].1;
+            } else {
+                let value = effective[current.len()].1;
+                available -= value;
+                // excluding a UTXO and then including one of the same value gives the same set
+                if current.last() == Some(&false) && effective[current.len() - 1].1 == value {
+                    current.push(false);
+                } else {
+                    current.push(true);
+                    current_value += value;
+                }
+            }
+        }
+
+        let (included, _) = best?;
+        let selected: Vec<Utxo> = included
+            .iter()
+            .zip(effective.iter())
+            .filter(|(include, _)| **include)
+            .map(|(_, (i, _))| utxos[*i].clone())
+            .collect();
+        let total: u64 = selected.iter().map(|utxo| self.value(utxo)).sum();
+
+        Some(self.selection(&selected, total, total - target.as_sat(), 0))
+    }
+
+    fn selection(&self, utxos: &[Utxo], total: u64, fee: u64, change: u64) -> Selection {
+        Selection {
+            utxos: utxos.to_vec(),
+            total: Amount::from_sat(total),
+            fee: Amount::from_sat(fee),
+            change: Amount::from_sat(change),
+        }
+    }
+
+    /// Selects spendable wallet UTXOs and locks them in the daemon. The lock is released when
+    /// the returned [LockedSelection] is dropped without being broadcast, or by the next
+    /// selection after it timed out.
+    pub fn select_and_lock<'a, C: RpcApi>(
+        &self,
+        client: &'a C,
+        target: Amount,
+        outputs: usize,
+    ) -> Result<LockedSelection<'a, C>> {
+        let _selecting = self.selecting.lock().unwrap_or_else(|e| e.into_inner());
+        self.release_expired(client)?;
+
+        // the daemon leaves locked UTXOs out of listunspent
+        let utxos = client
+            .list_unspent(Some(self.min_conf), None, None)?
+            .iter()
+            .filter(|unspent| unspent.spendable)
+            .map(Utxo::from_unspent)
+            .collect::<Result<Vec<Utxo>>>()?;
+
+        let selection = self.select(&utxos, target, outputs)?;
+        if !client.lock_unspent(&selection.outpoints())? {
+            return Err(Error::KMDError(String::from(
+                "could not lock the selected UTXOs",
+            )));
+        }
+
+        let lock = Arc::new(Lock {
+            outpoints: selection.outpoints(),
+            deadline: Instant::now() + self.lock_timeout,
+            released: AtomicBool::new(false),
+        });
+        self.locks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(Arc::clone(&lock));
+
+        Ok(LockedSelection {
+            client,
+            selection,
+            lock,
+        })
+    }
+
+    /// Unlocks the UTXOs of selections that timed out and returns how many selections were
+    /// released. Runs on every selection; call it to release them without selecting.
+    pub fn release_expired<C: RpcApi>(&self, client: &C) -> Result<usize> {
+        let mut locks = self.locks.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+
+        let mut released = 0;
+        for lock in locks.iter().filter(|lock| now >= lock.deadline) {
+            if lock.take() {
+                if let Err(e) = client.unlock_unspent(&lock.outpoints) {
+                    lock.released.store(false, Ordering::SeqCst);
+                    return Err(e);
+                }
+                released += 1;
+            }
+        }
+        locks.retain(|lock| !lock.is_released());
+
+        Ok(released)
+    }
+}
+
+// Shared between a selector and the selection, whichever releases it first unlocks the UTXOs.
+struct Lock {
+    outpoints: Vec<OutPoint>,
+    deadline: Instant,
+    released: AtomicBool,
+}
+
+impl Lock {
+    /// Whether the caller is the one to unlock the UTXOs.
+    fn take(&self) -> bool {
+        !self.released.swap(true, Ordering::SeqCst)
+    }
+
+    fn is_released(&self) -> bool {
+        self.released.load(Ordering::SeqCst)
+    }
+}
+
+/// UTXOs that are locked in the daemon until they are broadcast, released or the lock
+/// times out.
+///
+/// A timed out lock is released by the next selection of the [CoinSelector], or when the
+/// selection is dropped or broadcast. Locks are kept in the daemon's memory, so a restart of the
+/// daemon releases them as well.
+pub struct LockedSelection<'a, C: RpcApi> {
+    client: &'a C,
+    selection: Selection,
+    lock: Arc<Lock>,
+}
+
+impl<'a, C: RpcApi> LockedSelection<'a, C> {
+    pub fn selection(&self) -> &Selection {
+        &self.selection
+    }
+
+    pub fn inputs(&self) -> Vec<CreateRawTransactionInput> {
+        self.selection.inputs()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.lock.is_released() || Instant::now() >= self.lock.deadline
+    }
+
+    /// Broadcasts a signed transaction that spends the selection. Fails without broadcasting
+    /// when the lock has timed out, because the UTXOs could have been selected by someone else.
+    pub fn broadcast(self, signed_hex: &str) -> Result<Txid> {
+        // taken first, so the selector can't release the lock while the transaction is sent
+        if self.is_expired() || !self.lock.take() {
+            return Err(Error::KMDError(String::from(
+                "lock on the selected UTXOs timed out",
+            )));
+        }
+
+        match self.client.sendrawtransaction(signed_hex, None) {
+            // spent outputs can't be selected anymore, so there is nothing left to unlock
+            Ok(txid) => Ok(txid),
+            Err(e) => {
+                let _ = self.client.unlock_unspent(&self.lock.outpoints);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn release(self) -> Result<()> {
+        if self.lock.take() {
+            self.client.unlock_unspent(&self.lock.outpoints)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a, C: RpcApi> Drop for LockedSelection<'a, C> {
+    fn drop(&mut self) {
+        if self.lock.take() {
+            let _ = self.client.unlock_unspent(&self.lock.outpoints);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{unexpected, MockRpc};
+    use serde_json::json;
+
+    fn utxo(vout: u32, sats: u64, interest: u64, confirmations: u32) -> Utxo {
+        Utxo {
+            outpoint: OutPoint::new(Txid::default(), vout),
+            amount: Amount::from_sat(sats),
+            interest: Amount::from_sat(interest),
+            confirmations,
+        }
+    }
+
+    #[test]
+    fn branch_and_bound_finds_changeless_selection() {
+        let selector = CoinSelector::new(Strategy::BranchAndBound, InterestPreference::Ignore);
+        let utxos = vec![
+            utxo(0, 500_000_000, 0, 10),
+            utxo(1, 200_000_000, 0, 10),
+            utxo(2, 100_000_000, 0, 10),
+        ];
+        let target = Amount::from_sat(300_000_000) - selector.fee(2, 1);
+
+        let selection = selector.select(&utxos, target, 1).unwrap();
+        assert_eq!(selection.outpoints().len(), 2);
+        assert_eq!(selection.change, Amount::from_sat(0));
+        assert_eq!(selection.total, Amount::from_sat(300_000_000));
+    }
+
+    #[test]
+    fn largest_and_oldest_first_order() {
+        let utxos = vec![utxo(0, 100_000_000, 0, 50), utxo(1, 300_000_000, 0, 5)];
+
+        let selector = CoinSelector::new(Strategy::LargestFirst, InterestPreference::Ignore);
+        let selection = selector
+            .select(&utxos, Amount::from_sat(50_000_000), 1)
+            .unwrap();
+        assert_eq!(selection.utxos[0].outpoint.vout, 1);
+
+        let selector = CoinSelector::new(Strategy::OldestFirst, InterestPreference::Ignore);
+        let selection = selector
+            .select(&utxos, Amount::from_sat(50_000_000), 1)
+            .unwrap();
+        assert_eq!(selection.utxos[0].outpoint.vout, 0);
+        assert!(selection.change > Amount::from_sat(0));
+    }
+
+    #[test]
+    fn dust_change_goes_to_fee() {
+        let selector = CoinSelector::new(Strategy::LargestFirst, InterestPreference::Ignore);
+        let utxos = vec![utxo(0, 100_000_000, 0, 10)];
+        let target = Amount::from_sat(100_000_000 - 100) - selector.fee(1, 1);
+
+        let selection = selector.select(&utxos, target, 1).unwrap();
+        assert_eq!(selection.change, Amount::from_sat(0));
+        assert_eq!(selection.fee, selector.fee(1, 1) + Amount::from_sat(100));
+    }
+
+    #[test]
+    fn interest_preference() {
+        let utxos = vec![
+            utxo(0, 1_000_000_000, 5_000_000, 100),
+            utxo(1, 200_000_000, 0, 100),
+        ];
+
+        let selector = CoinSelector::new(Strategy::LargestFirst, InterestPreference::Avoid);
+        assert!(selector
+            .select(&utxos, Amount::from_sat(500_000_000), 1)
+            .is_err());
+
+        let selector = CoinSelector::new(Strategy::LargestFirst, InterestPreference::Prefer);
+        let selection = selector
+            .select(&utxos, Amount::from_sat(100_000_000), 1)
+            .unwrap();
+        assert_eq!(selection.utxos[0].outpoint.vout, 0);
+        assert_eq!(selection.total, Amount::from_sat(1_005_000_000));
+    }
+
+    #[test]
+    fn expired_locks_are_released_by_the_next_selection() {
+        let client = MockRpc::new(|cmd, _| match cmd {
+            "listunspent" => Ok(json!([{
+                "txid": "11".repeat(32),
+                "vout": 0,
+                "generated": false,
+                "scriptPubKey": format!("76a914{}88ac", "00".repeat(20)),
+                "amount": 10.0,
+                "confirmations": 10,
+                "spendable": true
+            }])),
+            "lockunspent" => Ok(json!(true)),
+            _ => unexpected(cmd),
+        });
+        let unlocks = || {
+            client
+                .calls("lockunspent")
+                .iter()
+                .filter(|args| args[0] == json!(true))
+                .count()
+        };
+        let mut selector = CoinSelector::new(Strategy::LargestFirst, InterestPreference::Ignore);
+        selector.lock_timeout = Duration::from_secs(0);
+
+        let first = selector
+            .select_and_lock(&client, Amount::from_sat(100_000_000), 1)
+            .unwrap();
+        assert!(first.is_expired());
+        assert_eq!(unlocks(), 0);
+
+        let second = selector
+            .select_and_lock(&client, Amount::from_sat(100_000_000), 1)
+            .unwrap();
+        assert_eq!(unlocks(), 1);
+        // already released by the selector
+        drop(first);
+        assert_eq!(unlocks(), 1);
+
+        assert!(second.broadcast("00").is_err());
+        assert_eq!(unlocks(), 2);
+        assert!(client.calls("sendrawtransaction").is_empty());
+    }
+}
@@ -0,0 +1,581 @@
+//! Consolidating many small UTXOs into a few, and splitting a balance into many UTXOs of the
+//! same size (fan-out), e.g. for notarization splits.
+//!
+//! Both operations are planned first, so the transactions can be inspected before they are
+//! created, signed and broadcast by [Consolidator::execute].
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use crate::bitcoin::hashes::hex::{FromHex, ToHex};
+use crate::bitcoin::{OutPoint, Txid};
+use crate::client::rewards_locktime;
+use crate::coin_selection::{
+    estimate_fee, estimate_size, CoinSelector, InterestPreference, Strategy, Utxo,
+    DEFAULT_DUST_THRESHOLD, DEFAULT_FEE_RATE, INPUT_SIZE, MAX_STANDARD_TX_SIZE, OUTPUT_SIZE,
+    TX_OVERHEAD_SIZE,
+};
+use crate::json::komodo::util::amount::Amount;
+use crate::json::Address;
+use crate::{Error, Result, RpcApi};
+
+/// A transaction that pays `outputs` to a single address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PlannedTransaction {
+    pub inputs: Vec<Utxo>,
+    pub outputs: Vec<Amount>,
+    pub fee: Amount,
+    /// Whether the inputs accrued KMD rewards, which are claimed by setting a recent locktime.
+    pub claims_interest: bool,
+}
+
+impl PlannedTransaction {
+    pub fn outpoints(&self) -> Vec<OutPoint> {
+        self.inputs.iter().map(|utxo| utxo.outpoint).collect()
+    }
+
+    pub fn estimated_size(&self) -> usize {
+        estimate_size(self.inputs.len(), self.outputs.len())
+    }
+}
+
+/// What [`Consolidator::execute`] sent. Plans are sent in order, up to the first one that
+/// fails.
+#[derive(Debug)]
+pub struct Execution {
+    /// The transactions of the plans that were broadcast, in plan order.
+    pub sent: Vec<Txid>,
+    /// The index of the plan that failed and the error. The plans after it weren't sent.
+    pub failed: Option<(usize, Error)>,
+}
+
+impl Execution {
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_none()
+    }
+
+    /// The sent transactions, or the error of the failed plan.
+    pub fn into_result(self) -> Result<Vec<Txid>> {
+        match self.failed {
+            Some((_, error)) => Err(error),
+            None => Ok(self.sent),
+        }
+    }
+}
+
+pub struct Consolidator {
+    /// No planned transaction is larger than this.
+    pub max_tx_size: usize,
+    /// Satoshis per 1000 bytes.
+    pub fee_rate: Amount,
+    /// No output is smaller than this.
+    pub dust_threshold: Amount,
+    pub min_conf: usize,
+}
+
+impl Default for Consolidator {
+    fn default() -> Self {
+        Consolidator {
+            max_tx_size: MAX_STANDARD_TX_SIZE,
+            fee_rate: Amount::from_sat(DEFAULT_FEE_RATE),
+            dust_threshold: Amount::from_sat(DEFAULT_DUST_THRESHOLD),
+            min_conf: 1,
+        }
+    }
+}
+
+impl Consolidator {
+    /// Plans the consolidation of `utxos` into `outputs` outputs in total, smallest UTXOs first.
+    /// Only UTXOs below `below` are consolidated, when it is given.
+    ///
+    /// When the UTXOs don't fit in `outputs` transactions of `max_tx_size`, every transaction
+    /// gets a single output and the result has more than `outputs` outputs. Consolidating
+    /// again reduces them further.
+    pub fn plan_consolidation(
+        &self,
+        utxos: &[Utxo],
+        outputs: usize,
+        below: Option<Amount>,
+    ) -> Result<Vec<PlannedTransaction>> {
+        if outputs == 0 {
+            return Err(Error::KMDError(String::from(
+                "consolidation needs at least one output",
+            )));
+        }
+
+        let mut candidates: Vec<&Utxo> = utxos
+            .iter()
+            .filter(|utxo| below.map_or(true, |below| utxo.amount < below))
+            .collect();
+        candidates.sort_by_key(|utxo| utxo.amount);
+
+        let max_inputs = self.max_inputs(outputs.max(1))?;
+        let chunks: Vec<&[&Utxo]> = candidates.chunks(max_inputs).collect();
+        if chunks.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let per_tx = outputs / chunks.len();
+        let remainder = outputs % chunks.len();
+
+        chunks
+            .iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                let n_outputs = (per_tx + if i < remainder { 1 } else { 0 }).max(1);
+                let inputs: Vec<Utxo> = chunk.iter().map(|utxo| (*utxo).clone()).collect();
+                self.split_evenly(inputs, n_outputs)
+            })
+            .collect()
+    }
+
+    /// Plans `count` outputs of `amount` each, funded by the largest UTXOs. UTXOs that accrued
+    /// rewards are left alone, claim them first with `claim_rewards`.
+    ///
+    /// The change of every transaction is an additional output to the same address.
+    pub fn plan_fan_out(
+        &self,
+        utxos: &[Utxo],
+        count: usize,
+        amount: Amount,
+    ) -> Result<Vec<PlannedTransaction>> {
+        if amount < self.dust_threshold {
+            return Err(Error::KMDError(String::from(
+                "fan-out amount is below the dust threshold",
+            )));
+        }
+
+        let selector = CoinSelector {
+            strategy: Strategy::LargestFirst,
+            interest: InterestPreference::Avoid,
+            dust_threshold: self.dust_threshold,
+            fee_rate: self.fee_rate,
+            ..Default::default()
+        };
+
+        // leave half of every transaction for its inputs
+        let outputs_per_tx = ((self.max_tx_size / 2).saturating_sub(TX_OVERHEAD_SIZE)
+            / OUTPUT_SIZE)
+            .saturating_sub(1)
+            .max(1);
+
+        let mut remaining: Vec<Utxo> = utxos.to_vec();
+        let mut plans = vec![];
+        let mut left = count;
+
+        while left > 0 {
+            let n = left.min(outputs_per_tx);
+            let target = Amount::from_sat(amount.as_sat() * n as u64);
+            let selection = selector.select(&remaining, target, n)?;
+
+            let mut outputs = vec![amount; n];
+            if selection.change > Amount::from_sat(0) {
+                outputs.push(selection.change);
+            }
+
+            let plan = PlannedTransaction {
+                inputs: selection.utxos,
+                outputs,
+                fee: selection.fee,
+                claims_interest: false,
+            };
+            if plan.estimated_size() > self.max_tx_size {
+                return Err(Error::KMDError(String::from(
+                    "fan-out transaction exceeds the maximum transaction size",
+                )));
+            }
+
+            let spent = plan.outpoints();
+            remaining.retain(|utxo| !spent.contains(&utxo.outpoint));
+            plans.push(plan);
+            left -= n;
+        }
+
+        Ok(plans)
+    }
+
+    /// Plans the split of all of `utxos` into `count` outputs of the same size.
+    pub fn plan_split(&self, utxos: &[Utxo], count: usize) -> Result<Vec<PlannedTransaction>> {
+        let max_inputs = self.max_inputs(count)?;
+        if utxos.len() > max_inputs {
+            return Err(Error::KMDError(format!(
+                "a split can spend at most {} UTXOs, consolidate them first",
+                max_inputs
+            )));
+        }
+
+        let mut inputs = utxos.to_vec();
+        inputs.sort_by_key(|utxo| Reverse(utxo.amount));
+
+        Ok(vec![self.split_evenly(inputs, count)?])
+    }
+
+    fn max_inputs(&self, outputs: usize) -> Result<usize> {
+        let available = self
+            .max_tx_size
+            .saturating_sub(TX_OVERHEAD_SIZE + outputs * OUTPUT_SIZE);
+        match available / INPUT_SIZE {
+            0 => Err(Error::KMDError(String::from(
+                "too many outputs for the maximum transaction size",
+            ))),
+            n => Ok(n),
+        }
+    }
+
+    fn split_evenly(&self, inputs: Vec<Utxo>, outputs: usize) -> Result<PlannedTransaction> {
+        let claims_interest = inputs.iter().any(Utxo::has_interest);
+        let total: u64 = inputs
+            .iter()
+            .map(|utxo| utxo.amount.as_sat() + utxo.interest.as_sat())
+            .sum();
+        let fee = estimate_fee(self.fee_rate, inputs.len(), outputs);
+
+        let value = total.saturating_sub(fee.as_sat());
+        let each = value / outputs as u64;
+        if each < self.dust_threshold.as_sat() {
+            return Err(Error::KMDError(format!(
+                "{} UTXOs are not enough to fund {} outputs",
+                inputs.len(),
+                outputs
+            )));
+        }
+
+        // the remainder of the division goes to the first output
+        let mut amounts = vec![Amount::from_sat(each); outputs];
+        amounts[0] = Amount::from_sat(each + value % outputs as u64);
+
+        Ok(PlannedTransaction {
+            inputs,
+            outputs: amounts,
+            fee,
+            claims_interest,
+        })
+    }
+
+    /// Consolidates the spendable wallet UTXOs at any address into `outputs` outputs at
+    /// `address`.
+    pub fn consolidate<C: RpcApi>(
+        &self,
+        client: &C,
+        address: &Address,
+        outputs: usize,
+        below: Option<Amount>,
+    ) -> Result<Execution> {
+        let utxos = self.wallet_utxos(client)?;
+        let plans = self.plan_consolidation(&utxos, outputs, below)?;
+
+        self.execute(client, address, &plans)
+    }
+
+    /// Creates `count` UTXOs of `amount` at `address`.
+    pub fn fan_out<C: RpcApi>(
+        &self,
+        client: &C,
+        address: &Address,
+        count: usize,
+        amount: Amount,
+    ) -> Result<Execution> {
+        let utxos = self.wallet_utxos(client)?;
+        let plans = self.plan_fan_out(&utxos, count, amount)?;
+
+        self.execute(client, address, &plans)
+    }
+
+    /// Splits the spendable wallet UTXOs into `count` UTXOs of the same size at `address`.
+    pub fn split<C: RpcApi>(
+        &self,
+        client: &C,
+        address: &Address,
+        count: usize,
+    ) -> Result<Execution> {
+        let utxos = self.wallet_utxos(client)?;
+        let plans = self.plan_split(&utxos, count)?;
+
+        self.execute(client, address, &plans)
+    }
+
+    fn wallet_utxos<C: RpcApi>(&self, client: &C) -> Result<Vec<Utxo>> {
+        client
+            .list_unspent(Some(self.min_conf), None, None)?
+            .iter()
+            .filter(|unspent| unspent.spendable)
+            .map(Utxo::from_unspent)
+            .collect()
+    }
+
+    /// Creates, signs and broadcasts the planned transactions, paying all outputs to `address`.
+    ///
+    /// The inputs of all plans are locked while the transactions are sent, so other payers
+    /// can't select them in the meantime. Sending stops at the first plan that fails; the
+    /// returned [`Execution`] tells which transactions went out before it.
+    pub fn execute<C: RpcApi>(
+        &self,
+        client: &C,
+        address: &Address,
+        plans: &[PlannedTransaction],
+    ) -> Result<Execution> {
+        let outpoints: Vec<OutPoint> = plans.iter().flat_map(|plan| plan.outpoints()).collect();
+        if !client.lock_unspent(&outpoints)? {
+            return Err(Error::KMDError(String::from(
+                "could not lock the planned UTXOs",
+            )));
+        }
+
+        let mut execution = Execution {
+            sent: vec![],
+            failed: None,
+        };
+        for (i, plan) in plans.iter().enumerate() {
+            match self.send(client, address, plan) {
+                Ok(txid) => execution.sent.push(txid),
+                Err(e) => {
+                    execution.failed = Some((i, e));
+                    break;
+                }
+            }
+        }
+
+        // only the inputs of plans that weren't sent are still unspent
+        let _ = client.unlock_unspent(&outpoints);
+
+        Ok(execution)
+    }
+
+    fn send<C: RpcApi>(
+        &self,
+        client: &C,
+        address: &Address,
+        plan: &PlannedTransaction,
+    ) -> Result<Txid> {
+        let inputs: Vec<_> = plan
+            .inputs
+            .iter()
+            .map(|utxo| crate::json::CreateRawTransactionInput {
+                txid: utxo.outpoint.txid,
+                vout: utxo.outpoint.vout,
+                sequence: None,
+            })
+            .collect();
+        let locktime = if plan.claims_interest {
+            Some(rewards_locktime()?)
+        } else {
+            None
+        };
+
+        // createrawtransaction takes a map of addresses, so it can only create one output per
+        // address. The other outputs are added to the raw transaction afterwards.
+        let mut first = HashMap::new();
+        first.insert(address.to_string(), plan.outputs[0]);
+        let mut hex = client.createrawtransaction(&inputs, &first, locktime, None)?;
+        if plan.outputs.len() > 1 {
+            hex = repeat_output(&hex, &plan.outputs)?;
+        }
+
+        let signed = client.signrawtransaction(&hex, None, None, None)?;
+        if !signed.complete {
+            return Err(Error::KMDError(String::from(
+                "wallet could not sign all inputs of the planned transaction",
+            )));
+        }
+
+        client.sendrawtransaction(&signed.hex, None)
+    }
+}
+
+/// Replaces the single output of an unsigned transparent transaction with outputs of
+/// `amounts`, all paying to the script of the original output.
+fn repeat_output(hex: &str, amounts: &[Amount]) -> Result<String> {
+    let invalid = || Error::KMDError(String::from("could not parse raw transaction"));
+    let bytes: Vec<u8> = FromHex::from_hex(hex).map_err(|_| invalid())?;
+
+    let mut pos = 4;
+    // overwintered transactions (v3 and up) have a version group id after the version
+    if bytes.len() < 4 || bytes[3] & 0x80 != 0 {
+        pos += 4;
+    }
+
+    let n_inputs = read_compact_size(&bytes, &mut pos).ok_or_else(invalid)?;
+    for _ in 0..n_inputs {
+        pos += 36;
+        let script_len = read_compact_size(&bytes, &mut pos).ok_or_else(invalid)?;
+        pos += script_len as usize + 4;
+    }
+
+    let outputs_start = pos;
+    if read_compact_size(&bytes, &mut pos) != Some(1) {
+        return Err(invalid());
+    }
+    pos += 8;
+    let script_start = pos;
+    let script_len = read_compact_size(&bytes, &mut pos).ok_or_else(invalid)? as usize;
+    let script_end = pos + script_len;
+    if script_end > bytes.len() {
+        return Err(invalid());
+    }
+    let script = &bytes[script_start..script_end];
+
+    let mut tx = bytes[..outputs_start].to_vec();
+    write_compact_size(&mut tx, amounts.len() as u64);
+    for amount in amounts {
+        tx.extend_from_slice(&amount.as_sat().to_le_bytes());
+        tx.extend_from_slice(script);
+    }
+    tx.extend_from_slice(&bytes[script_end..]);
+
+    Ok(tx.to_hex())
+}
+
+fn read_compact_size(bytes: &[u8], pos: &mut usize) -> Option<u64> {
+    let first = *bytes.get(*pos)?;
+    let len = match first {
+        0xfd => 2,
+        0xfe => 4,
+        0xff => 8,
+        n => {
+            *pos += 1;
+            return Some(n as u64);
+        }
+    };
+    let data = bytes.get(*pos + 1..*pos + 1 + len)?;
+    *pos += 1 + len;
+
+    Some(
+        data.iter()
+            .rev()
+            .fold(0u64, |acc, byte| (acc << 8) | *byte as u64),
+    )
+}
+
+fn write_compact_size(buf: &mut Vec<u8>, n: u64) {
+    match n {
+        0..=0xfc => buf.push(n as u8),
+        0xfd..=0xffff => {
+            buf.push(0xfd);
+            buf.extend_from_slice(&(n as u16).to_le_bytes());
+        }
+        0x10000..=0xffff_ffff => {
+            buf.push(0xfe);
+            buf.extend_from_slice(&(n as u32).to_le_bytes());
+        }
+        _ => {
+            buf.push(0xff);
+            buf.extend_from_slice(&n.to_le_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::mock::{rpc_error, txid, unexpected, MockRpc};
+
+    fn utxo(vout: u32, sats: u64) -> Utxo {
+        Utxo {
+            outpoint: OutPoint::new(Txid::default(), vout),
+            amount: Amount::from_sat(sats),
+            interest: Amount::from_sat(0),
+            confirmations: 10,
+        }
+    }
+
+    #[test]
+    fn consolidation_respects_max_tx_size() {
+        let consolidator = Consolidator {
+            max_tx_size: 1000,
+            ..Default::default()
+        };
+        let utxos: Vec<Utxo> = (0..20).map(|i| utxo(i, 1_000_000)).collect();
+
+        let plans = consolidator.plan_consolidation(&utxos, 2, None).unwrap();
+        assert!(plans.len() > 1);
+        for plan in &plans {
+            assert!(plan.estimated_size() <= 1000);
+        }
+        let spent: usize = plans.iter().map(|plan| plan.inputs.len()).sum();
+        assert_eq!(spent, 20);
+    }
+
+    #[test]
+    fn split_creates_equal_outputs() {
+        let consolidator = Consolidator::default();
+        let utxos = vec![utxo(0, 1_000_000_000)];
+
+        let plans = consolidator.plan_split(&utxos, 4).unwrap();
+        let outputs = &plans[0].outputs;
+        assert_eq!(outputs.len(), 4);
+        assert_eq!(outputs[1], outputs[3]);
+        let total: u64 = outputs.iter().map(|amount| amount.as_sat()).sum();
+        assert_eq!(total + plans[0].fee.as_sat(), 1_000_000_000);
+    }
+
+    #[test]
+    fn repeat_output_in_raw_transaction() {
+        // v4 transaction with one input and one P2PKH output of 1 KMD
+        let hex = "0400008085202f8901\
+            0000000000000000000000000000000000000000000000000000000000000000\
+            0000000000ffffffff\
+            0100e1f505000000001976a914000000000000000000000000000000000000000088ac\
+            00000000000000000000000000000000000000";
+
+        let amounts = [Amount::from_sat(1), Amount::from_sat(2)];
+        let repeated = repeat_output(hex, &amounts).unwrap();
+        assert!(repeated.contains(
+            "02\
+            01000000000000001976a914000000000000000000000000000000000000000088ac\
+            02000000000000001976a914000000000000000000000000000000000000000088ac"
+        ));
+        assert!(repeated.ends_with("00000000000000000000000000000000000000"));
+    }
+
+    #[test]
+    fn execute_reports_what_was_sent() {
+        let sent = Rc::new(Cell::new(0));
+        let client = {
+            let sent = Rc::clone(&sent);
+            MockRpc::new(move |cmd, _| match cmd {
+                "lockunspent" => Ok(json!(true)),
+                "createrawtransaction" => Ok(json!("00")),
+                "signrawtransaction" => Ok(json!({ "hex": "00", "complete": true })),
+                "sendrawtransaction" => {
+                    sent.set(sent.get() + 1);
+                    match sent.get() {
+                        1 => Ok(json!(txid(1))),
+                        _ => Err(rpc_error(-26, "18: txn-mempool-conflict")),
+                    }
+                }
+                _ => unexpected(cmd),
+            })
+        };
+        let address: Address =
+            serde_json::from_value(json!("RAqS1bAuWqW2f6ufsU5H4XpKfy5Pqj2oHz")).unwrap();
+        let plans: Vec<PlannedTransaction> = (0..3)
+            .map(|i| PlannedTransaction {
+                inputs: vec![utxo(i, 100_000_000)],
+                outputs: vec![Amount::from_sat(99_990_000)],
+                fee: Amount::from_sat(10_000),
+                claims_interest: false,
+            })
+            .collect();
+
+        let execution = Consolidator::default()
+            .execute(&client, &address, &plans)
+            .unwrap();
+        assert_eq!(execution.sent, vec![txid(1)]);
+        assert_eq!(execution.failed.as_ref().map(|(i, _)| *i), Some(1));
+        assert!(!execution.is_complete());
+        // the third plan isn't sent
+        assert_eq!(sent.get(), 2);
+
+        // all inputs are unlocked again
+        let locks = client.calls("lockunspent");
+        assert_eq!(locks.len(), 2);
+        assert_eq!(locks[1][0], json!(true));
+        assert_eq!(locks[1][1].as_array().unwrap().len(), 3);
+        assert!(execution.into_result().is_err());
+    }
+}
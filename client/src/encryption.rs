@@ -0,0 +1,143 @@
+//! Wallet encryption: a passphrase type that is wiped from memory, and a guard that keeps the
+//! wallet unlocked only for as long as it is needed.
+
+use std::fmt;
+use std::time::Duration;
+
+use zeroize::Zeroizing;
+
+use crate::{Result, RpcApi};
+
+/// A wallet passphrase. Its memory is zeroed when it is dropped and it is never printed.
+///
+/// The passphrase is copied into the JSON-RPC request when it is sent to the daemon; that copy
+/// is out of reach of this type.
+#[derive(Clone)]
+pub struct Passphrase(Zeroizing<String>);
+
+impl Passphrase {
+    pub fn new(passphrase: String) -> Self {
+        Passphrase(Zeroizing::new(passphrase))
+    }
+
+    pub(crate) fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Passphrase {
+    fn from(passphrase: String) -> Self {
+        Passphrase::new(passphrase)
+    }
+}
+
+impl fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Passphrase(<redacted>)")
+    }
+}
+
+/// Keeps the wallet unlocked until it is dropped, or until the unlock duration has passed,
+/// whichever comes first.
+pub struct UnlockedWallet<'a, C: RpcApi> {
+    client: &'a C,
+    locked: bool,
+}
+
+impl<'a, C: RpcApi> UnlockedWallet<'a, C> {
+    /// Unlocks the wallet for at most `duration` (rounded up to whole seconds).
+    pub fn unlock(client: &'a C, passphrase: &Passphrase, duration: Duration) -> Result<Self> {
+        let timeout = duration.as_secs() + if duration.subsec_nanos() > 0 { 1 } else { 0 };
+        client.wallet_passphrase(passphrase, timeout)?;
+
+        Ok(UnlockedWallet {
+            client,
+            locked: false,
+        })
+    }
+
+    pub fn client(&self) -> &C {
+        self.client
+    }
+
+    /// Locks the wallet and returns the daemon's error, which dropping the guard ignores.
+    pub fn lock(mut self) -> Result<()> {
+        self.locked = true;
+        self.client.wallet_lock()
+    }
+}
+
+impl<'a, C: RpcApi> Drop for UnlockedWallet<'a, C> {
+    fn drop(&mut self) {
+        if !self.locked {
+            let _ = self.client.wallet_lock();
+        }
+    }
+}
+
+/// Unlocks the wallet, runs `f` and locks the wallet again, also when `f` fails or panics.
+pub fn with_unlocked_wallet<C, F, T>(
+    client: &C,
+    passphrase: &Passphrase,
+    duration: Duration,
+    f: F,
+) -> Result<T>
+where
+    C: RpcApi,
+    F: FnOnce(&C) -> Result<T>,
+{
+    let unlocked = UnlockedWallet::unlock(client, passphrase, duration)?;
+    let result = f(unlocked.client());
+    let locked = unlocked.lock();
+
+    // the error of `f` is more interesting than the error of locking
+    let value = result?;
+    locked?;
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{unexpected, MockRpc};
+    use serde_json::{json, Value};
+
+    fn client() -> MockRpc {
+        MockRpc::new(|cmd, _| match cmd {
+            "walletpassphrase" | "walletlock" => Ok(Value::Null),
+            _ => unexpected(cmd),
+        })
+    }
+
+    #[test]
+    fn passphrase_is_not_printed() {
+        let passphrase = Passphrase::new(String::from("correct horse battery staple"));
+        let debug = format!("{:?}", passphrase);
+        assert!(!debug.contains("horse"));
+        assert_eq!(debug, "Passphrase(<redacted>)");
+    }
+
+    #[test]
+    fn dropping_the_guard_locks_the_wallet() {
+        let client = client();
+        let passphrase = Passphrase::new(String::from("secret"));
+
+        let unlocked =
+            UnlockedWallet::unlock(&client, &passphrase, Duration::from_millis(1500)).unwrap();
+        assert_eq!(
+            client.calls("walletpassphrase"),
+            vec![vec![json!("secret"), json!(2)]]
+        );
+        assert!(client.calls("walletlock").is_empty());
+
+        drop(unlocked);
+        assert_eq!(client.calls("walletlock").len(), 1);
+
+        // locking explicitly doesn't lock a second time on drop
+        let unlocked =
+            UnlockedWallet::unlock(&client, &passphrase, Duration::from_secs(1)).unwrap();
+        unlocked.lock().unwrap();
+        assert_eq!(client.calls("walletlock").len(), 2);
+    }
+}
@@ -13,6 +13,12 @@ pub enum Error {
     Json(serde_json::error::Error),
     KMDError(String),
     InvalidAmount(komodo::util::amount::ParseAmountError),
+    Base64(base64::DecodeError),
+    InvalidMemo(komodo_rpc_json::MemoError),
+    #[cfg(feature = "zmq")]
+    Zmq(zmq::Error),
+    #[cfg(feature = "sqlite")]
+    Sqlite(rusqlite::Error),
 }
 
 impl error::Error for Error {
@@ -25,6 +31,12 @@ impl error::Error for Error {
             Error::Json(ref e) => Some(e),
             Error::KMDError(_) => None,
             Error::InvalidAmount(ref e) => Some(e),
+            Error::Base64(ref e) => Some(e),
+            Error::InvalidMemo(ref e) => Some(e),
+            #[cfg(feature = "zmq")]
+            Error::Zmq(ref e) => Some(e),
+            #[cfg(feature = "sqlite")]
+            Error::Sqlite(ref e) => Some(e),
         }
     }
 }
@@ -39,6 +51,12 @@ impl fmt::Display for Error {
             Error::Json(ref e) => write!(f, "JSON error: {}", e),
             Error::KMDError(ref e) => write!(f, "KMD daemon error: {}", e),
             Error::InvalidAmount(ref e) => write!(f, "invalid amount: {}", e),
+            Error::Base64(ref e) => write!(f, "base64 error: {}", e),
+            Error::InvalidMemo(ref e) => write!(f, "invalid memo: {}", e),
+            #[cfg(feature = "zmq")]
+            Error::Zmq(ref e) => write!(f, "ZMQ error: {}", e),
+            #[cfg(feature = "sqlite")]
+            Error::Sqlite(ref e) => write!(f, "SQLite error: {}", e),
         }
     }
 }
@@ -72,3 +90,29 @@ impl From<komodo::util::amount::ParseAmountError> for Error {
         Error::InvalidAmount(e)
     }
 }
+
+impl From<base64::DecodeError> for Error {
+    fn from(e: base64::DecodeError) -> Error {
+        Error::Base64(e)
+    }
+}
+
+impl From<komodo_rpc_json::MemoError> for Error {
+    fn from(e: komodo_rpc_json::MemoError) -> Error {
+        Error::InvalidMemo(e)
+    }
+}
+
+#[cfg(feature = "zmq")]
+impl From<zmq::Error> for Error {
+    fn from(e: zmq::Error) -> Error {
+        Error::Zmq(e)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Error {
+        Error::Sqlite(e)
+    }
+}
@@ -0,0 +1,393 @@
+//! Waiting for transactions to become final, with dPoW notarization as the confirmation target.
+//!
+//! A block that has been notarized can't be reorganized away anymore. On asset chains, that
+//! guarantee only extends to BTC once the KMD block holding the notarization is notarized on
+//! BTC itself, which is what [`FinalityPolicy::BackNotarized`] waits for.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::bitcoin::{BlockHash, Txid};
+use crate::{Error, Result, RpcApi};
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(10);
+/// The error code komodod returns for unknown transactions.
+const RPC_INVALID_ADDRESS_OR_KEY: i32 = -5;
+
+/// When a transaction counts as final.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FinalityPolicy {
+    /// The transaction has this many confirmations, not adjusted for dPoW.
+    Confirmations(u32),
+    /// The block holding the transaction is notarized: on BTC for KMD, on KMD for asset chains.
+    Notarized,
+    /// The block holding the transaction is notarized and the notarization is secured by BTC.
+    /// The same as `Notarized` on KMD.
+    BackNotarized,
+}
+
+impl FinalityPolicy {
+    pub fn is_met(&self, progress: &FinalityProgress) -> bool {
+        match *self {
+            FinalityPolicy::Confirmations(n) => progress.raw_confirmations >= n,
+            FinalityPolicy::Notarized => progress.notarized,
+            FinalityPolicy::BackNotarized => progress.back_notarized,
+        }
+    }
+}
+
+/// Where a transaction stands on its way to finality.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FinalityProgress {
+    pub txid: Txid,
+    /// `None` while the transaction is in the mempool.
+    pub blockhash: Option<BlockHash>,
+    pub height: Option<u32>,
+    pub raw_confirmations: u32,
+    pub notarized: bool,
+    pub back_notarized: bool,
+    /// How often the block holding the transaction was reorganized away while waiting.
+    pub reorgs: u32,
+}
+
+impl FinalityProgress {
+    pub fn in_mempool(&self) -> bool {
+        self.blockhash.is_none()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FinalityOutcome {
+    Final(FinalityProgress),
+    TimedOut(FinalityProgress),
+    /// The transaction left the mempool and the chain, and the wallet knows transactions that
+    /// spend the same inputs.
+    Conflicted {
+        progress: FinalityProgress,
+        conflicts: Vec<Txid>,
+    },
+    /// The transaction left the mempool and the chain, for instance because it expired.
+    Dropped(FinalityProgress),
+}
+
+/// Follows a transaction until it is final. Requires `-txindex` for transactions that are not in
+/// the wallet.
+pub struct Finality<'a, C: RpcApi> {
+    client: &'a C,
+    txid: Txid,
+    policy: FinalityPolicy,
+    poll_interval: Duration,
+}
+
+impl<'a, C: RpcApi> Finality<'a, C> {
+    pub fn new(client: &'a C, txid: Txid, policy: FinalityPolicy) -> Self {
+        Finality {
+            client,
+            txid,
+            policy,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+        }
+    }
+
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// The current progress, `None` when the daemon doesn't know the transaction. `previous` is
+    /// used to detect reorgs.
+    pub fn check(&self, previous: Option<&FinalityProgress>) -> Result<Option<FinalityProgress>> {
+        let tx = match self.client.get_raw_transaction_verbose(&self.txid) {
+            Ok(tx) => tx,
+            Err(ref e) if is_unknown_transaction(e) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let raw_confirmations = tx.rawconfirmations.unwrap_or(0);
+        // a block that isn't in the active chain anymore has no confirmations
+        let blockhash = tx.blockhash.filter(|_| raw_confirmations > 0);
+        let height = match (blockhash, tx.height) {
+            (Some(_), Some(height)) => Some(height),
+            (Some(hash), None) => Some(self.client.get_block(&hash)?.height),
+            (None, _) => None,
+        };
+
+        let (notarized, back_notarized) = match height {
+            Some(height) => {
+                let info = self.client.get_notarization_info()?;
+                let notarized = height <= info.notarized;
+                (notarized, notarized && info.is_btc_final())
+            }
+            None => (false, false),
+        };
+
+        let mut reorgs = previous.map_or(0, |p| p.reorgs);
+        if let Some(previous_hash) = previous.and_then(|p| p.blockhash) {
+            if blockhash != Some(previous_hash) {
+                reorgs += 1;
+            }
+        }
+
+        Ok(Some(FinalityProgress {
+            txid: self.txid,
+            blockhash,
+            height,
+            raw_confirmations: if blockhash.is_some() {
+                raw_confirmations
+            } else {
+                0
+            },
+            notarized,
+            back_notarized,
+            reorgs,
+        }))
+    }
+
+    /// Polls the transaction until it is final, it timed out, or it left the mempool and the
+    /// chain. `on_progress` is called whenever the progress changed.
+    pub fn wait<F>(&self, timeout: Duration, mut on_progress: F) -> Result<FinalityOutcome>
+    where
+        F: FnMut(&FinalityProgress),
+    {
+        let deadline = Instant::now() + timeout;
+        let mut last: Option<FinalityProgress> = None;
+
+        loop {
+            let progress = match self.check(last.as_ref())? {
+                Some(progress) => progress,
+                None => {
+                    let progress = last.ok_or_else(|| {
+                        Error::KMDError(format!(
+                            "transaction {} is unknown to the daemon",
+                            self.txid
+                        ))
+                    })?;
+                    let conflicts = self.wallet_conflicts()?;
+
+                    return Ok(if conflicts.is_empty() {
+                        FinalityOutcome::Dropped(progress)
+                    } else {
+                        FinalityOutcome::Conflicted {
+                            progress,
+                            conflicts,
+                        }
+                    });
+                }
+            };
+
+            if last.as_ref() != Some(&progress) {
+                on_progress(&progress);
+            }
+            if self.policy.is_met(&progress) {
+                return Ok(FinalityOutcome::Final(progress));
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(FinalityOutcome::TimedOut(progress));
+            }
+            last = Some(progress);
+            thread::sleep(self.poll_interval.min(deadline - now));
+        }
+    }
+
+    fn wallet_conflicts(&self) -> Result<Vec<Txid>> {
+        match self.client.get_transaction(&self.txid, Some(true)) {
+            Ok(tx) => Ok(tx.walletconflicts.into_iter().flatten().collect()),
+            Err(ref e) if is_unknown_transaction(e) => Ok(vec![]),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+pub(crate) fn is_unknown_transaction(error: &Error) -> bool {
+    match *error {
+        Error::JsonRPC(jsonrpc::Error::Rpc(ref e)) => e.code == RPC_INVALID_ADDRESS_OR_KEY,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{rpc_error, txid, unexpected, MockRpc};
+    use serde_json::{json, Value};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Default)]
+    struct Daemon {
+        tx: Option<Value>,
+        notarized: u32,
+        // KMD height of the last notarization, `None` while it is in the KMD mempool
+        notarizedtxid_height: Option<u32>,
+        kmd_notarized: u32,
+        notarized_confirms: u32,
+        // `None` when the wallet doesn't know the transaction
+        conflicts: Option<Vec<Txid>>,
+    }
+
+    fn client(daemon: &Rc<RefCell<Daemon>>) -> MockRpc {
+        let daemon = Rc::clone(daemon);
+        MockRpc::new(move |cmd, _| {
+            let daemon = daemon.borrow();
+            match cmd {
+                "getrawtransaction" => daemon
+                    .tx
+                    .clone()
+                    .ok_or_else(|| rpc_error(-5, "No information available about transaction")),
+                "getinfo" => Ok(json!({
+                    "name": "DOC",
+                    "notarized": daemon.notarized,
+                    "prevMoMheight": 0,
+                    "notarizedhash": "00".repeat(32),
+                    "notarizedtxid": "00".repeat(32),
+                    "notarizedtxid_height": daemon
+                        .notarizedtxid_height
+                        .map_or(json!("mempool"), |height| json!(height)),
+                    "KMDnotarized_height": daemon.kmd_notarized,
+                    "notarized_confirms": daemon.notarized_confirms
+                })),
+                "gettransaction" => match daemon.conflicts {
+                    Some(ref conflicts) => Ok(json!({
+                        "amount": 0.0,
+                        "rawconfirmations": 0,
+                        "confirmations": 0,
+                        "txid": txid(1),
+                        "walletconflicts": conflicts,
+                        "time": 0,
+                        "timereceived": 0,
+                        "vjoinsplit": [],
+                        "hex": ""
+                    })),
+                    None => Err(rpc_error(-5, "Invalid or non-wallet transaction id")),
+                },
+                _ => unexpected(cmd),
+            }
+        })
+    }
+
+    // `blockhash` is left out while the transaction is in the mempool
+    fn tx(block: Option<(BlockHash, u32)>, confirmations: u32) -> Value {
+        let mut tx = json!({
+            "hex": "",
+            "txid": txid(1),
+            "version": 4,
+            "locktime": 0,
+            "expiryheight": 0,
+            "vin": [],
+            "vout": [],
+            "vjoinsplit": [],
+            "rawconfirmations": confirmations,
+            "confirmations": confirmations
+        });
+        if let Some((hash, height)) = block {
+            tx["blockhash"] = json!(hash);
+            tx["height"] = json!(height);
+        }
+        tx
+    }
+
+    fn hash(n: u8) -> BlockHash {
+        BlockHash::from_inner([n; 32])
+    }
+
+    #[test]
+    fn counts_reorgs() {
+        let daemon = Rc::new(RefCell::new(Daemon::default()));
+        let client = client(&daemon);
+        let finality = Finality::new(&client, txid(1), FinalityPolicy::Confirmations(10));
+
+        daemon.borrow_mut().tx = Some(tx(Some((hash(1), 100)), 2));
+        let first = finality.check(None).unwrap().unwrap();
+        assert_eq!(first.reorgs, 0);
+        assert_eq!(first.height, Some(100));
+
+        // mined again in another block at the same height
+        daemon.borrow_mut().tx = Some(tx(Some((hash(2), 100)), 1));
+        let second = finality.check(Some(&first)).unwrap().unwrap();
+        assert_eq!(second.reorgs, 1);
+        assert_eq!(second.blockhash, Some(hash(2)));
+
+        // back in the mempool, the old block has no confirmations
+        daemon.borrow_mut().tx = Some(tx(Some((hash(2), 100)), 0));
+        let third = finality.check(Some(&second)).unwrap().unwrap();
+        assert!(third.in_mempool());
+        assert_eq!(third.raw_confirmations, 0);
+        assert_eq!(third.reorgs, 2);
+
+        let fourth = finality.check(Some(&third)).unwrap().unwrap();
+        assert_eq!(fourth.reorgs, 2);
+    }
+
+    #[test]
+    fn notarized_and_back_notarized() {
+        let daemon = Rc::new(RefCell::new(Daemon::default()));
+        let client = client(&daemon);
+        let finality = Finality::new(&client, txid(1), FinalityPolicy::Notarized);
+        daemon.borrow_mut().tx = Some(tx(Some((hash(1), 100)), 5));
+
+        daemon.borrow_mut().notarized = 99;
+        let progress = finality.check(None).unwrap().unwrap();
+        assert!(!progress.notarized && !progress.back_notarized);
+
+        daemon.borrow_mut().notarized = 100;
+        let progress = finality.check(None).unwrap().unwrap();
+        assert!(progress.notarized);
+        assert!(!progress.back_notarized);
+        assert!(FinalityPolicy::Notarized.is_met(&progress));
+        assert!(!FinalityPolicy::BackNotarized.is_met(&progress));
+        assert!(FinalityPolicy::Confirmations(5).is_met(&progress));
+
+        // confirmed on KMD, but not in a KMD block that is notarized on BTC
+        daemon.borrow_mut().notarized_confirms = 1;
+        let progress = finality.check(None).unwrap().unwrap();
+        assert!(!progress.back_notarized);
+
+        daemon.borrow_mut().notarizedtxid_height = Some(2_000);
+        daemon.borrow_mut().kmd_notarized = 1_999;
+        let progress = finality.check(None).unwrap().unwrap();
+        assert!(!progress.back_notarized);
+        assert!(!FinalityPolicy::BackNotarized.is_met(&progress));
+
+        daemon.borrow_mut().kmd_notarized = 2_000;
+        let progress = finality.check(None).unwrap().unwrap();
+        assert!(progress.back_notarized);
+        assert!(FinalityPolicy::BackNotarized.is_met(&progress));
+    }
+
+    fn wait_until_gone(conflicts: Option<Vec<Txid>>) -> FinalityOutcome {
+        let daemon = Rc::new(RefCell::new(Daemon {
+            tx: Some(tx(None, 0)),
+            conflicts,
+            ..Default::default()
+        }));
+        let client = client(&daemon);
+
+        Finality::new(&client, txid(1), FinalityPolicy::Confirmations(1))
+            .poll_interval(Duration::from_millis(0))
+            // the transaction leaves the mempool after it was seen once
+            .wait(Duration::from_secs(10), |_| daemon.borrow_mut().tx = None)
+            .unwrap()
+    }
+
+    #[test]
+    fn dropped_or_conflicted() {
+        match wait_until_gone(None) {
+            FinalityOutcome::Dropped(progress) => assert!(progress.in_mempool()),
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+
+        match wait_until_gone(Some(vec![txid(2)])) {
+            FinalityOutcome::Conflicted { conflicts, .. } => assert_eq!(conflicts, vec![txid(2)]),
+            outcome => panic!("unexpected outcome {:?}", outcome),
+        }
+
+        // never seen at all
+        let daemon = Rc::new(RefCell::new(Daemon::default()));
+        let client = client(&daemon);
+        assert!(Finality::new(&client, txid(1), FinalityPolicy::Notarized)
+            .wait(Duration::from_secs(10), |_| {})
+            .is_err());
+    }
+}
@@ -0,0 +1,673 @@
+//! A local SQLite index of the chain.
+//!
+//! The [`Indexer`] follows the chain with a [`ChainFollower`] and stores blocks, transactions,
+//! their inputs and outputs, the balance of every transparent address and the notarizations in
+//! each block. Blocks that leave the active chain are removed again. Indexing needs `-txindex`,
+//! and the balances are only complete when the index starts at the genesis block. Shielded
+//! values aren't indexed.
+
+use std::path::Path;
+use std::str::FromStr;
+
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+
+use crate::bitcoin::{BlockHash, Txid};
+use crate::chain_follower::{ChainEvent, ChainFollower, Start, StopAt};
+use crate::json::komodo::util::amount::{Amount, SignedAmount};
+use crate::json::{Address, Block, GetRawTransactionResultVerbose};
+use crate::notarization::{self, Notarization};
+use crate::{Error, Result, RpcApi};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS blocks (
+    height INTEGER PRIMARY KEY,
+    hash TEXT NOT NULL UNIQUE,
+    time INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS transactions (
+    txid TEXT PRIMARY KEY,
+    height INTEGER NOT NULL,
+    position INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS transactions_height ON transactions (height);
+CREATE TABLE IF NOT EXISTS inputs (
+    txid TEXT NOT NULL,
+    n INTEGER NOT NULL,
+    prev_txid TEXT NOT NULL,
+    prev_vout INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    PRIMARY KEY (txid, n)
+);
+CREATE INDEX IF NOT EXISTS inputs_height ON inputs (height);
+CREATE TABLE IF NOT EXISTS outputs (
+    txid TEXT NOT NULL,
+    n INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    address TEXT,
+    value INTEGER NOT NULL,
+    script TEXT NOT NULL,
+    spent_txid TEXT,
+    spent_height INTEGER,
+    PRIMARY KEY (txid, n)
+);
+CREATE INDEX IF NOT EXISTS outputs_height ON outputs (height);
+CREATE INDEX IF NOT EXISTS outputs_spent_height ON outputs (spent_height);
+CREATE INDEX IF NOT EXISTS outputs_address ON outputs (address);
+CREATE TABLE IF NOT EXISTS balances (
+    address TEXT PRIMARY KEY,
+    balance INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS balances_balance ON balances (balance);
+CREATE TABLE IF NOT EXISTS notarizations (
+    txid TEXT PRIMARY KEY,
+    height INTEGER NOT NULL,
+    symbol TEXT NOT NULL,
+    notarized_height INTEGER NOT NULL,
+    notarized_hash TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS notarizations_height ON notarizations (height);
+";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexedBlock {
+    pub height: u64,
+    pub hash: BlockHash,
+    pub time: u64,
+}
+
+impl From<&Block> for IndexedBlock {
+    fn from(block: &Block) -> Self {
+        IndexedBlock {
+            height: block.height as u64,
+            hash: block.hash,
+            time: block.time,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexedTransaction {
+    pub txid: Txid,
+    pub height: u64,
+    /// Position in the block.
+    pub position: u32,
+}
+
+/// The net effect of a transaction on an address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AddressHistoryEntry {
+    pub txid: Txid,
+    pub height: u64,
+    pub delta: SignedAmount,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RichListEntry {
+    pub address: String,
+    pub balance: Amount,
+}
+
+/// A notarization transaction and the block it notarizes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NotarizationMarker {
+    pub txid: Txid,
+    pub height: u64,
+    pub symbol: String,
+    pub notarized_height: u64,
+    pub notarized_hash: BlockHash,
+}
+
+pub struct Indexer {
+    conn: Connection,
+}
+
+impl Indexer {
+    /// Opens the index at `path`, creating it if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Indexer::with_connection(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self> {
+        Indexer::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(conn: Connection) -> Result<Self> {
+        conn.execute_batch(SCHEMA)?;
+        Ok(Indexer { conn })
+    }
+
+    /// The last indexed block.
+    pub fn tip(&self) -> Result<Option<IndexedBlock>> {
+        let row = self
+            .conn
+            .query_row(
+                "SELECT height, hash, time FROM blocks ORDER BY height DESC LIMIT 1",
+                params![],
+                |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, i64>(2)?,
+                    ))
+                },
+            )
+            .optional()?;
+
+        match row {
+            Some((height, hash, time)) => Ok(Some(IndexedBlock {
+                height: height as u64,
+                hash: parse_hash(&hash)?,
+                time: time as u64,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    /// Indexes the chain up to `stop_at` and returns the number of blocks that were connected
+    /// or disconnected. Call it again to keep up with the chain.
+    pub fn sync<C: RpcApi>(&mut self, client: &C, stop_at: StopAt) -> Result<u64> {
+        let mut events = 0;
+
+        // blocks that were reorganized while the index wasn't following the chain
+        let mut tip = self.tip()?;
+        while let Some(block) = tip {
+            if block.height <= client.get_block_count()? as u64
+                && client.get_block_hash(block.height)? == block.hash
+            {
+                tip = Some(block);
+                break;
+            }
+            self.disconnect(block.height)?;
+            events += 1;
+            tip = self.tip()?;
+        }
+
+        let start = match tip {
+            Some(block) => Start::After(block.hash),
+            None => Start::Height(0),
+        };
+        let mut follower = ChainFollower::new(client, start).stop_at(stop_at);
+        while let Some(event) = follower.next_event()? {
+            match event {
+                ChainEvent::Connected(block) => {
+                    // the genesis transaction can't be looked up
+                    let txs = if block.height == 0 {
+                        vec![]
+                    } else {
+                        block
+                            .tx
+                            .iter()
+                            .map(|txid| client.get_raw_transaction_verbose(txid))
+                            .collect::<Result<Vec<_>>>()?
+                    };
+                    let notarizations = notarization::find_notarizations(
+                        client,
+                        block.height as u64,
+                        block.time,
+                        &txs,
+                    )?;
+                    self.connect(&IndexedBlock::from(&block), &txs, &notarizations)?;
+                }
+                ChainEvent::Disconnected(block) => self.disconnect(block.height as u64)?,
+            }
+            events += 1;
+        }
+
+        Ok(events)
+    }
+
+    /// Adds a block on top of the index. `notarizations` are the notarizations among `txs`, as
+    /// found by [`find_notarizations`](notarization::find_notarizations).
+    pub fn connect(
+        &mut self,
+        block: &IndexedBlock,
+        txs: &[GetRawTransactionResultVerbose],
+        notarizations: &[Notarization],
+    ) -> Result<()> {
+        let height = block.height as i64;
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO blocks (height, hash, time) VALUES (?1, ?2, ?3)",
+            params![height, block.hash.to_string(), block.time as i64],
+        )?;
+
+        for (position, raw) in txs.iter().enumerate() {
+            let txid = raw.txid.to_string();
+            tx.execute(
+                "INSERT INTO transactions (txid, height, position) VALUES (?1, ?2, ?3)",
+                params![txid, height, position as i64],
+            )?;
+
+            for (n, vin) in raw.vin.iter().enumerate() {
+                if vin.is_coinbase() {
+                    continue;
+                }
+                let prev_txid = vin.txid.to_string();
+                tx.execute(
+                    "INSERT INTO inputs (txid, n, prev_txid, prev_vout, height)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![txid, n as i64, prev_txid, vin.vout as i64, height],
+                )?;
+
+                // outputs from before the start of the index aren't known
+                let spent = tx
+                    .query_row(
+                        "SELECT address, value FROM outputs WHERE txid = ?1 AND n = ?2",
+                        params![prev_txid, vin.vout as i64],
+                        |row| Ok((row.get::<_, Option<String>>(0)?, row.get::<_, i64>(1)?)),
+                    )
+                    .optional()?;
+                tx.execute(
+                    "UPDATE outputs SET spent_txid = ?1, spent_height = ?2
+                     WHERE txid = ?3 AND n = ?4",
+                    params![txid, height, prev_txid, vin.vout as i64],
+                )?;
+                if let Some((Some(address), value)) = spent {
+                    add_balance(&tx, &address, -value)?;
+                }
+            }
+
+            for vout in &raw.vout {
+                // multisig outputs don't belong to a single address
+                let address = match vout.script_pubkey.addresses {
+                    Some(ref addresses) if addresses.len() == 1 => Some(addresses[0].to_string()),
+                    _ => None,
+                };
+                let value = vout.value.as_sat() as i64;
+                tx.execute(
+                    "INSERT INTO outputs (txid, n, height, address, value, script)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![
+                        txid,
+                        vout.n as i64,
+                        height,
+                        address,
+                        value,
+                        vout.script_pubkey.hex
+                    ],
+                )?;
+                if let Some(ref address) = address {
+                    add_balance(&tx, address, value)?;
+                }
+            }
+        }
+
+        for notarization in notarizations {
+            tx.execute(
+                "INSERT INTO notarizations (txid, height, symbol, notarized_height, notarized_hash)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    notarization.txid.to_string(),
+                    height,
+                    notarization.symbol(),
+                    notarization.notarized_height() as i64,
+                    notarization.prev_hash().to_string()
+                ],
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Removes the block at `height`, which has to be the tip, from the index.
+    pub fn disconnect(&mut self, height: u64) -> Result<()> {
+        let height = height as i64;
+        let tx = self.conn.transaction()?;
+
+        let spent = address_values(
+            &tx,
+            "SELECT address, value FROM outputs WHERE spent_height = ?1 AND address IS NOT NULL",
+            height,
+        )?;
+        for (address, value) in spent {
+            add_balance(&tx, &address, value)?;
+        }
+        tx.execute(
+            "UPDATE outputs SET spent_txid = NULL, spent_height = NULL WHERE spent_height = ?1",
+            params![height],
+        )?;
+
+        let created = address_values(
+            &tx,
+            "SELECT address, value FROM outputs WHERE height = ?1 AND address IS NOT NULL",
+            height,
+        )?;
+        for (address, value) in created {
+            add_balance(&tx, &address, -value)?;
+        }
+
+        for table in &[
+            "outputs",
+            "inputs",
+            "transactions",
+            "notarizations",
+            "blocks",
+        ] {
+            tx.execute(
+                &format!("DELETE FROM {} WHERE height = ?1", table),
+                params![height],
+            )?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn balance(&self, address: &Address) -> Result<Amount> {
+        let balance = self
+            .conn
+            .query_row(
+                "SELECT balance FROM balances WHERE address = ?1",
+                params![address.to_string()],
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()?;
+        Ok(Amount::from_sat(balance.unwrap_or(0) as u64))
+    }
+
+    /// The `limit` addresses with the highest balances.
+    pub fn rich_list(&self, limit: usize) -> Result<Vec<RichListEntry>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT address, balance FROM balances ORDER BY balance DESC LIMIT ?1")?;
+        let rows = stmt
+            .query_map(params![limit as i64], |row| {
+                Ok(RichListEntry {
+                    address: row.get(0)?,
+                    balance: Amount::from_sat(row.get::<_, i64>(1)? as u64),
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(rows)
+    }
+
+    /// The transactions that paid to or spent from `address`, oldest first.
+    pub fn address_history(&self, address: &Address) -> Result<Vec<AddressHistoryEntry>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT h.txid, h.height, SUM(h.delta) FROM (
+                 SELECT txid, height, value AS delta FROM outputs WHERE address = ?1
+                 UNION ALL
+                 SELECT spent_txid, spent_height, -value FROM outputs
+                 WHERE address = ?1 AND spent_txid IS NOT NULL
+             ) h
+             JOIN transactions t ON t.txid = h.txid
+             GROUP BY h.txid, h.height
+             ORDER BY h.height, t.position",
+        )?;
+        let rows = stmt
+            .query_map(params![address.to_string()], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, i64>(2)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        rows.into_iter()
+            .map(|(txid, height, delta)| {
+                Ok(AddressHistoryEntry {
+                    txid: parse_hash(&txid)?,
+                    height: height as u64,
+                    delta: SignedAmount::from_sat(delta),
+                })
+            })
+            .collect()
+    }
+
+    /// The transactions in the blocks from `from` to `to`, both inclusive.
+    pub fn transactions(&self, from: u64, to: u64) -> Result<Vec<IndexedTransaction>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT txid, height, position FROM transactions
+             WHERE height BETWEEN ?1 AND ?2 ORDER BY height, position",
+        )?;
+        let rows = stmt
+            .query_map(params![from as i64, to as i64], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, i64>(2)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        rows.into_iter()
+            .map(|(txid, height, position)| {
+                Ok(IndexedTransaction {
+                    txid: parse_hash(&txid)?,
+                    height: height as u64,
+                    position: position as u32,
+                })
+            })
+            .collect()
+    }
+
+    /// The notarizations in the blocks from `from` to `to`, both inclusive.
+    pub fn notarizations(&self, from: u64, to: u64) -> Result<Vec<NotarizationMarker>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT n.txid, n.height, n.symbol, n.notarized_height, n.notarized_hash
+             FROM notarizations n JOIN transactions t ON t.txid = n.txid
+             WHERE n.height BETWEEN ?1 AND ?2 ORDER BY n.height, t.position",
+        )?;
+        let rows = stmt
+            .query_map(params![from as i64, to as i64], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, i64>(3)?,
+                    row.get::<_, String>(4)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        rows.into_iter()
+            .map(|(txid, height, symbol, notarized_height, notarized_hash)| {
+                Ok(NotarizationMarker {
+                    txid: parse_hash(&txid)?,
+                    height: height as u64,
+                    symbol,
+                    notarized_height: notarized_height as u64,
+                    notarized_hash: parse_hash(&notarized_hash)?,
+                })
+            })
+            .collect()
+    }
+}
+
+fn add_balance(tx: &Transaction, address: &str, delta: i64) -> Result<()> {
+    // upserts need SQLite 3.24, which older systems don't ship
+    tx.execute(
+        "INSERT OR IGNORE INTO balances (address, balance) VALUES (?1, 0)",
+        params![address],
+    )?;
+    tx.execute(
+        "UPDATE balances SET balance = balance + ?2 WHERE address = ?1",
+        params![address, delta],
+    )?;
+    tx.execute(
+        "DELETE FROM balances WHERE address = ?1 AND balance = 0",
+        params![address],
+    )?;
+    Ok(())
+}
+
+fn address_values(tx: &Transaction, sql: &str, height: i64) -> Result<Vec<(String, i64)>> {
+    let mut stmt = tx.prepare(sql)?;
+    let rows = stmt
+        .query_map(params![height], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows)
+}
+
+fn parse_hash<T: FromStr>(hex: &str) -> Result<T> {
+    hex.parse()
+        .map_err(|_| Error::KMDError(format!("invalid hash {} in the index", hex)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::mock::{rpc_error, unexpected, MockChain, MockRpc};
+
+    const ALICE: &str = "RAqS1bAuWqW2f6ufsU5H4XpKfy5Pqj2oHz";
+    const BOB: &str = "RCPfwfTXRrCVFCT9GmAS2YJSafhC2XqEbD";
+
+    fn transaction(txid: u8, vin: &str, vout: &[(&str, f64)]) -> GetRawTransactionResultVerbose {
+        let vout: Vec<String> = vout
+            .iter()
+            .enumerate()
+            .map(|(n, (address, value))| {
+                format!(
+                    r#"{{"value": {}, "n": {}, "scriptPubKey": {{"asm": "", "hex": "", "type": "pubkeyhash", "addresses": ["{}"]}}}}"#,
+                    value, n, address
+                )
+            })
+            .collect();
+
+        serde_json::from_str(&format!(
+            r#"{{
+                "hex": "",
+                "txid": "{}",
+                "version": 1,
+                "locktime": 0,
+                "expiryheight": 0,
+                "vin": [{}],
+                "vout": [{}],
+                "vjoinsplit": []
+            }}"#,
+            format!("{:02x}", txid).repeat(32),
+            vin,
+            vout.join(",")
+        ))
+        .unwrap()
+    }
+
+    fn block(height: u64) -> IndexedBlock {
+        IndexedBlock {
+            height,
+            hash: parse_hash(&format!("{:064x}", height)).unwrap(),
+            time: height * 60,
+        }
+    }
+
+    #[test]
+    fn connect_and_disconnect() {
+        let alice: Address = serde_json::from_str(&format!("\"{}\"", ALICE)).unwrap();
+        let bob: Address = serde_json::from_str(&format!("\"{}\"", BOB)).unwrap();
+        let mut indexer = Indexer::open_in_memory().unwrap();
+
+        let coinbase = transaction(
+            1,
+            r#"{"coinbase": "00", "sequence": 4294967295}"#,
+            &[(ALICE, 10.0)],
+        );
+        indexer.connect(&block(1), &[coinbase], &[]).unwrap();
+
+        let spend = transaction(
+            2,
+            &format!(
+                r#"{{"txid": "{}", "vout": 0, "scriptSig": {{"asm": "", "hex": ""}}, "sequence": 4294967295}}"#,
+                "01".repeat(32)
+            ),
+            &[(BOB, 4.0), (ALICE, 6.0)],
+        );
+        indexer.connect(&block(2), &[spend], &[]).unwrap();
+
+        assert_eq!(indexer.tip().unwrap(), Some(block(2)));
+        assert_eq!(
+            indexer.balance(&alice).unwrap(),
+            Amount::from_sat(600_000_000)
+        );
+        assert_eq!(
+            indexer.balance(&bob).unwrap(),
+            Amount::from_sat(400_000_000)
+        );
+        assert_eq!(indexer.rich_list(1).unwrap()[0].address, ALICE);
+
+        let history = indexer.address_history(&alice).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].delta, SignedAmount::from_sat(1_000_000_000));
+        assert_eq!(history[1].delta, SignedAmount::from_sat(-400_000_000));
+        assert_eq!(indexer.transactions(1, 2).unwrap().len(), 2);
+
+        indexer.disconnect(2).unwrap();
+        assert_eq!(indexer.tip().unwrap(), Some(block(1)));
+        assert_eq!(
+            indexer.balance(&alice).unwrap(),
+            Amount::from_sat(1_000_000_000)
+        );
+        assert_eq!(indexer.rich_list(10).unwrap().len(), 1);
+        assert_eq!(indexer.address_history(&alice).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn sync_follows_reorgs() {
+        let alice: Address = serde_json::from_str(&format!("\"{}\"", ALICE)).unwrap();
+        let bob: Address = serde_json::from_str(&format!("\"{}\"", BOB)).unwrap();
+        let chain = Rc::new(RefCell::new(MockChain::new(1)));
+        let txs = Rc::new(RefCell::new(HashMap::new()));
+        let client = {
+            let chain = Rc::clone(&chain);
+            let txs = Rc::clone(&txs);
+            MockRpc::new(move |cmd, args| {
+                if let Some(result) = chain.borrow().handle(cmd, args) {
+                    return result;
+                }
+                match cmd {
+                    "getrawtransaction" => {
+                        let txid: Txid = serde_json::from_value(args[0].clone()).unwrap();
+                        txs.borrow()
+                            .get(&txid)
+                            .map(|tx| Ok(serde_json::to_value(tx).unwrap()))
+                            .unwrap_or_else(|| Err(rpc_error(-5, "No such transaction")))
+                    }
+                    _ => unexpected(cmd),
+                }
+            })
+        };
+        // a block with a coinbase paying `value` to `address`
+        let mine = |n: u8, address: &str, value: f64| {
+            let coinbase = transaction(
+                n,
+                r#"{"coinbase": "00", "sequence": 4294967295}"#,
+                &[(address, value)],
+            );
+            chain.borrow_mut().push(&[coinbase.txid]);
+            txs.borrow_mut().insert(coinbase.txid, coinbase);
+        };
+
+        mine(1, ALICE, 10.0);
+        mine(2, ALICE, 10.0);
+        let mut indexer = Indexer::open_in_memory().unwrap();
+        assert_eq!(indexer.sync(&client, StopAt::Tip).unwrap(), 3);
+        assert_eq!(indexer.tip().unwrap().unwrap().hash, chain.borrow().tip());
+        assert_eq!(
+            indexer.balance(&alice).unwrap(),
+            Amount::from_sat(2_000_000_000)
+        );
+        assert_eq!(indexer.sync(&client, StopAt::Tip).unwrap(), 0);
+
+        // the last block is replaced while the indexer doesn't follow the chain
+        chain.borrow_mut().disconnect(1);
+        mine(3, BOB, 5.0);
+        mine(4, BOB, 5.0);
+        assert_eq!(indexer.sync(&client, StopAt::Tip).unwrap(), 3);
+        assert_eq!(indexer.tip().unwrap().unwrap().height, 3);
+        assert_eq!(indexer.tip().unwrap().unwrap().hash, chain.borrow().tip());
+        assert_eq!(
+            indexer.balance(&alice).unwrap(),
+            Amount::from_sat(1_000_000_000)
+        );
+        assert_eq!(
+            indexer.balance(&bob).unwrap(),
+            Amount::from_sat(1_000_000_000)
+        );
+        assert_eq!(indexer.transactions(2, 3).unwrap().len(), 2);
+    }
+}
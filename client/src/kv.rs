@@ -0,0 +1,93 @@
+//! Size and fee estimation for the on-chain key-value store of KV-enabled asset chains.
+//!
+//! A `kvupdate` pays [`estimate_fee`] on top of the regular transaction fee, plus
+//! [`UPDATE_OUTPUT`] sent to the burn address that carries the data.
+
+use crate::json::komodo::util::amount::Amount;
+use crate::{Error, Result};
+
+/// Keys can't be stored for longer than this.
+pub const MAX_DAYS: u32 = 365;
+/// Blocks per day of storage.
+pub const BLOCKS_PER_DAY: u32 = 1440;
+/// Lower bound of the storage fee, in satoshis.
+pub const MIN_FEE: u64 = 100_000;
+/// Value of the output carrying the update, in satoshis.
+pub const UPDATE_OUTPUT: u64 = 10_000;
+
+// keylen (2), valuesize (2), height (4), flags (4)
+const HEADER_SIZE: usize = 12;
+// pubkey hash and signature of a passphrase protected update
+const OWNER_SIZE: usize = 64;
+
+pub(crate) fn validate(key: &str, days: u32) -> Result<()> {
+    if key.is_empty() {
+        return Err(Error::KMDError(String::from("kv key can't be empty")));
+    }
+    if days == 0 {
+        return Err(Error::KMDError(String::from(
+            "kv update needs to last at least one day",
+        )));
+    }
+
+    Ok(())
+}
+
+/// Size of the OP_RETURN script of an update, as used by the fee calculation.
+pub fn opreturn_size(key: &str, value: &str, protected: bool) -> usize {
+    let mut data = HEADER_SIZE + key.len() + value.len();
+    if protected {
+        data += OWNER_SIZE;
+    }
+    // the 'K' marker is pushed along with the data
+    let push = data + 1;
+    let prefix = match push {
+        0..=0x4b => 1,
+        0x4c..=0xff => 2,
+        _ => 3,
+    };
+
+    // the daemon pads 40 byte scripts by one byte
+    match 1 + prefix + push {
+        40 => 41,
+        size => size,
+    }
+}
+
+/// The storage fee the daemon charges for an update. Grows with the square of the script size,
+/// is discounted by the key length (up to 32 bytes) and scales with the number of days.
+pub fn estimate_fee(key: &str, value: &str, days: u32, protected: bool) -> Amount {
+    let days = days.min(MAX_DAYS).max(1) as u64;
+    let size = opreturn_size(key, value, protected) as u64;
+    let keylen = key.len().min(32).max(1) as u64;
+
+    Amount::from_sat((days * (size * size / keylen)).max(MIN_FEE))
+}
+
+/// Number of blocks an update with `days` stays valid for.
+pub fn duration(days: u32) -> u32 {
+    days.min(MAX_DAYS).max(1) * BLOCKS_PER_DAY
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update_fee() {
+        assert_eq!(opreturn_size("examplekey", "examplevalue", false), 37);
+        assert_eq!(
+            estimate_fee("examplekey", "examplevalue", 1, false),
+            Amount::from_sat(MIN_FEE)
+        );
+
+        assert_eq!(opreturn_size("examplekey", "examplevalue", true), 102);
+        assert_eq!(
+            estimate_fee("examplekey", "examplevalue", 1000, true),
+            Amount::from_sat(365 * (102 * 102 / 10))
+        );
+
+        // 12 + 2 + 23 + 1 would make a 40 byte script
+        assert_eq!(opreturn_size("ab", &"x".repeat(23), false), 41);
+    }
+}
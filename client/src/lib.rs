@@ -12,8 +12,29 @@ pub extern crate komodo_rpc_json;
 pub use json::bitcoin;
 pub use komodo_rpc_json as json;
 
+pub mod chain_follower;
 mod client;
+pub mod coin_selection;
+pub mod consolidation;
+pub mod encryption;
 mod error;
+pub mod finality;
+#[cfg(feature = "sqlite")]
+pub mod indexer;
+pub mod kv;
+pub mod mempool_watcher;
+#[cfg(test)]
+mod mock;
+pub mod multisig;
+pub mod notarization;
+pub mod notary_monitor;
+pub mod notary_seasons;
+pub mod operation;
+pub mod partially_signed;
+pub mod payment_watcher;
+pub mod wallet_dump;
+#[cfg(feature = "zmq")]
+pub mod zmq_subscriber;
 
 pub use client::*;
 pub use error::Error;
@@ -0,0 +1,475 @@
+//! Watching the mempool for transactions that enter and leave it.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::bitcoin::Txid;
+use crate::finality::is_unknown_transaction;
+use crate::json::komodo::util::amount::Amount;
+use crate::{Error, Result, RpcApi};
+
+/// A transaction in the mempool.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MempoolEntry {
+    pub txid: Txid,
+    pub fee: Amount,
+    pub size: u32,
+    /// When the transaction entered the mempool.
+    pub time: u32,
+    /// Unconfirmed transactions this one spends from.
+    pub depends: Vec<Txid>,
+    /// 0 if the transaction doesn't expire.
+    pub expiryheight: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RemovalReason {
+    /// Included in a block.
+    Mined,
+    /// Not mined before its expiry height.
+    Expired,
+    /// Neither mined nor expired: another transaction spent the same inputs, or the node evicted
+    /// it.
+    Replaced,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MempoolEvent {
+    Added(MempoolEntry),
+    Removed {
+        entry: MempoolEntry,
+        reason: RemovalReason,
+    },
+}
+
+/// Diffs successive snapshots of the mempool. The first poll reports everything that is in the
+/// mempool as added.
+pub struct MempoolWatcher<C: RpcApi> {
+    client: C,
+    wallet_only: bool,
+    entries: HashMap<Txid, MempoolEntry>,
+    // transactions that were filtered out, so they aren't looked up again
+    ignored: HashSet<Txid>,
+    height: Option<u64>,
+}
+
+impl<C: RpcApi> MempoolWatcher<C> {
+    pub fn new(client: C) -> Self {
+        MempoolWatcher {
+            client,
+            wallet_only: false,
+            entries: HashMap::new(),
+            ignored: HashSet::new(),
+            height: None,
+        }
+    }
+
+    /// Only report transactions that involve the wallet.
+    pub fn wallet_only(mut self, wallet_only: bool) -> Self {
+        self.wallet_only = wallet_only;
+        self
+    }
+
+    pub fn client(&self) -> &C {
+        &self.client
+    }
+
+    /// The transactions in the last snapshot.
+    pub fn entries(&self) -> impl Iterator<Item = &MempoolEntry> {
+        self.entries.values()
+    }
+
+    /// Takes a new snapshot and returns how it differs from the previous one.
+    pub fn poll(&mut self) -> Result<Vec<MempoolEvent>> {
+        let mempool = self.client.get_raw_mempool_verbose()?;
+        let height = self.client.get_block_count()? as u64;
+        let mined = self.mined_since(height)?;
+        self.height = Some(height);
+
+        let mut events = vec![];
+
+        let removed: Vec<Txid> = self
+            .entries
+            .keys()
+            .filter(|txid| !mempool.contains(*txid))
+            .copied()
+            .collect();
+        for txid in removed {
+            let entry = self.entries.remove(&txid).expect("txid is a key");
+            // a transaction expires when it isn't in a block at or below its expiry height
+            let reason = if mined.contains(&txid) || self.is_mined(&txid)? {
+                RemovalReason::Mined
+            } else if entry.expiryheight != 0 && height + 1 > entry.expiryheight as u64 {
+                RemovalReason::Expired
+            } else {
+                RemovalReason::Replaced
+            };
+            events.push(MempoolEvent::Removed { entry, reason });
+        }
+
+        self.ignored.retain(|txid| mempool.contains(txid));
+
+        for (txid, info) in mempool.iter() {
+            if self.entries.contains_key(txid) || self.ignored.contains(txid) {
+                continue;
+            }
+            if self.wallet_only && !self.is_wallet_transaction(txid)? {
+                self.ignored.insert(*txid);
+                continue;
+            }
+
+            let tx = match self.client.get_raw_transaction_verbose(txid) {
+                Ok(tx) => tx,
+                // left the mempool since the snapshot
+                Err(ref e) if is_unknown_transaction(e) => continue,
+                Err(e) => return Err(e),
+            };
+
+            let entry = MempoolEntry {
+                txid: *txid,
+                fee: info.fee,
+                size: info.size,
+                time: info.time,
+                depends: info.depends.clone(),
+                expiryheight: tx.expiryheight,
+            };
+            self.entries.insert(*txid, entry.clone());
+            events.push(MempoolEvent::Added(entry));
+        }
+
+        Ok(events)
+    }
+
+    fn mined_since(&self, height: u64) -> Result<HashSet<Txid>> {
+        let mut mined = HashSet::new();
+        if let Some(previous) = self.height {
+            for h in previous + 1..=height {
+                let hash = self.client.get_block_hash(h)?;
+                mined.extend(self.client.get_block(&hash)?.tx);
+            }
+        }
+        Ok(mined)
+    }
+
+    // Blocks that arrive between the mempool snapshot and the block count aren't scanned, so
+    // transactions that weren't seen in a block are looked up before they count as replaced.
+    fn is_mined(&self, txid: &Txid) -> Result<bool> {
+        match self.client.get_raw_transaction_verbose(txid) {
+            Ok(tx) => Ok(tx.blockhash.is_some() && tx.rawconfirmations.unwrap_or(0) > 0),
+            Err(ref e) if is_unknown_transaction(e) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn is_wallet_transaction(&self, txid: &Txid) -> Result<bool> {
+        match self.client.get_transaction(txid, Some(true)) {
+            Ok(_) => Ok(true),
+            Err(ref e) if is_unknown_transaction(e) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<C: RpcApi + Send + 'static> MempoolWatcher<C> {
+    /// Polls in a background thread and sends the events into a channel that holds at most
+    /// `capacity` events. Polling pauses while the channel is full.
+    pub fn spawn(self, capacity: usize, poll_interval: Duration) -> MempoolSubscription {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || watch(self, sender, stop, poll_interval))
+        };
+
+        MempoolSubscription {
+            receiver,
+            stop,
+            handle,
+        }
+    }
+}
+
+fn watch<C: RpcApi>(
+    mut watcher: MempoolWatcher<C>,
+    sender: SyncSender<MempoolEvent>,
+    stop: Arc<AtomicBool>,
+    poll_interval: Duration,
+) -> Result<()> {
+    while !stop.load(Ordering::Relaxed) {
+        for event in watcher.poll()? {
+            if sender.send(event).is_err() {
+                // the subscription was dropped
+                return Ok(());
+            }
+        }
+        thread::sleep(poll_interval);
+    }
+    Ok(())
+}
+
+/// The receiving end of a [`MempoolWatcher`] running in the background.
+pub struct MempoolSubscription {
+    receiver: Receiver<MempoolEvent>,
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<Result<()>>,
+}
+
+impl MempoolSubscription {
+    /// Blocks until the next event. Returns `None` once the watcher stopped, for instance
+    /// because of an RPC error, which [`stop`](MempoolSubscription::stop) returns.
+    pub fn recv(&self) -> Option<MempoolEvent> {
+        self.receiver.recv().ok()
+    }
+
+    /// Like [`recv`](MempoolSubscription::recv), but gives up after `timeout`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<MempoolEvent> {
+        self.receiver.recv_timeout(timeout).ok()
+    }
+
+    pub fn try_recv(&self) -> Option<MempoolEvent> {
+        self.receiver.try_recv().ok()
+    }
+
+    /// Stops the watcher and returns the error it stopped on, if any.
+    pub fn stop(self) -> Result<()> {
+        self.stop.store(true, Ordering::Relaxed);
+        // unblocks the watcher if it waits for room in the channel
+        drop(self.receiver);
+        self.handle
+            .join()
+            .unwrap_or_else(|_| Err(Error::KMDError(String::from("mempool watcher panicked"))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::mock::{rpc_error, txid, unexpected, MockChain, MockRpc};
+
+    struct Node {
+        chain: MockChain,
+        // txid and expiry height
+        mempool: Vec<(Txid, u32)>,
+        wallet: Vec<Txid>,
+        // transactions in blocks
+        mined: Vec<Txid>,
+        // mined in a new block right after the next mempool snapshot
+        racing: Vec<Txid>,
+    }
+
+    fn client(node: &Rc<RefCell<Node>>) -> MockRpc {
+        let node = Rc::clone(node);
+        MockRpc::new(move |cmd, args| {
+            let result = answer(&node.borrow(), cmd, args);
+            if cmd == "getrawmempool" {
+                let mut node = node.borrow_mut();
+                let racing = std::mem::replace(&mut node.racing, vec![]);
+                if !racing.is_empty() {
+                    node.chain.push(&racing);
+                    node.mempool.retain(|(txid, _)| !racing.contains(txid));
+                    node.mined.extend(racing);
+                }
+            }
+            result
+        })
+    }
+
+    fn answer(node: &Node, cmd: &str, args: &[Value]) -> Result<Value> {
+        if let Some(result) = node.chain.handle(cmd, args) {
+            return result;
+        }
+        let requested = || -> Txid { serde_json::from_value(args[0].clone()).unwrap() };
+        match cmd {
+            "getrawmempool" => {
+                let mempool: HashMap<String, Value> = node
+                    .mempool
+                    .iter()
+                    .map(|(txid, _)| {
+                        let info = json!({
+                            "size": 250,
+                            "fee": 0.0001,
+                            "time": 1_600_000_000,
+                            "height": 9,
+                            "startingpriority": 0.0,
+                            "currentpriority": 0.0,
+                            "depends": []
+                        });
+                        (txid.to_string(), info)
+                    })
+                    .collect();
+                Ok(json!(mempool))
+            }
+            "getrawtransaction" => {
+                let txid = requested();
+                if node.mined.contains(&txid) {
+                    return Ok(json!({
+                        "hex": "",
+                        "txid": txid,
+                        "version": 4,
+                        "locktime": 0,
+                        "expiryheight": 0,
+                        "vin": [],
+                        "vout": [],
+                        "vjoinsplit": [],
+                        "blockhash": node.chain.tip(),
+                        "rawconfirmations": 1
+                    }));
+                }
+                match node.mempool.iter().find(|(t, _)| *t == txid) {
+                    Some((_, expiryheight)) => Ok(json!({
+                        "hex": "",
+                        "txid": txid,
+                        "version": 4,
+                        "locktime": 0,
+                        "expiryheight": expiryheight,
+                        "vin": [],
+                        "vout": [],
+                        "vjoinsplit": []
+                    })),
+                    None => Err(rpc_error(-5, "No information available about transaction")),
+                }
+            }
+            "gettransaction" => {
+                let txid = requested();
+                if node.wallet.contains(&txid) {
+                    Ok(json!({
+                        "amount": 1.0,
+                        "rawconfirmations": 0,
+                        "confirmations": 0,
+                        "txid": txid,
+                        "walletconflicts": [],
+                        "time": 1_600_000_000,
+                        "timereceived": 1_600_000_000,
+                        "vjoinsplit": [],
+                        "hex": ""
+                    }))
+                } else {
+                    Err(rpc_error(-5, "Invalid or non-wallet transaction id"))
+                }
+            }
+            _ => unexpected(cmd),
+        }
+    }
+
+    fn added(events: &[MempoolEvent]) -> Vec<Txid> {
+        let mut added: Vec<Txid> = events
+            .iter()
+            .filter_map(|event| match event {
+                MempoolEvent::Added(entry) => Some(entry.txid),
+                _ => None,
+            })
+            .collect();
+        added.sort();
+        added
+    }
+
+    fn removed(events: &[MempoolEvent]) -> HashMap<Txid, RemovalReason> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                MempoolEvent::Removed { entry, reason } => Some((entry.txid, *reason)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reports_added_and_removed() {
+        let node = Rc::new(RefCell::new(Node {
+            chain: MockChain::new(10),
+            mempool: vec![(txid(1), 0), (txid(2), 11), (txid(3), 10), (txid(4), 0)],
+            wallet: vec![],
+            mined: vec![],
+            racing: vec![],
+        }));
+        let mut watcher = MempoolWatcher::new(client(&node));
+
+        let events = watcher.poll().unwrap();
+        let mut expected: Vec<Txid> = (1..5).map(txid).collect();
+        expected.sort();
+        assert_eq!(added(&events), expected);
+        assert!(removed(&events).is_empty());
+        let entry = watcher.entries().find(|e| e.txid == txid(3)).unwrap();
+        assert_eq!(entry.expiryheight, 10);
+        assert_eq!(entry.fee, Amount::from_sat(10_000));
+
+        assert!(watcher.poll().unwrap().is_empty());
+
+        {
+            let mut node = node.borrow_mut();
+            // block 10 mines the first transaction, the third one expired at height 10
+            node.chain.push(&[txid(1)]);
+            node.mempool = vec![(txid(4), 0), (txid(5), 0)];
+        }
+        let events = watcher.poll().unwrap();
+        assert_eq!(added(&events), vec![txid(5)]);
+
+        let removed = removed(&events);
+        assert_eq!(removed.len(), 3);
+        assert_eq!(removed[&txid(1)], RemovalReason::Mined);
+        // still valid in block 11
+        assert_eq!(removed[&txid(2)], RemovalReason::Replaced);
+        assert_eq!(removed[&txid(3)], RemovalReason::Expired);
+        assert_eq!(watcher.entries().count(), 2);
+    }
+
+    #[test]
+    fn filters_wallet_transactions() {
+        let node = Rc::new(RefCell::new(Node {
+            chain: MockChain::new(10),
+            mempool: vec![(txid(1), 0), (txid(2), 0)],
+            wallet: vec![txid(1)],
+            mined: vec![],
+            racing: vec![],
+        }));
+        let mut watcher = MempoolWatcher::new(client(&node)).wallet_only(true);
+
+        assert_eq!(added(&watcher.poll().unwrap()), vec![txid(1)]);
+        assert_eq!(watcher.client().calls("gettransaction").len(), 2);
+
+        // the foreign transaction isn't looked up again
+        assert!(watcher.poll().unwrap().is_empty());
+        assert_eq!(watcher.client().calls("gettransaction").len(), 2);
+        assert_eq!(watcher.client().calls("getrawtransaction").len(), 1);
+
+        // nor reported when it leaves the mempool
+        node.borrow_mut().mempool = vec![(txid(1), 0)];
+        assert!(watcher.poll().unwrap().is_empty());
+
+        // but it is forgotten, and checked again when it comes back
+        node.borrow_mut().mempool = vec![(txid(1), 0), (txid(2), 0)];
+        assert!(watcher.poll().unwrap().is_empty());
+        assert_eq!(watcher.client().calls("gettransaction").len(), 3);
+    }
+
+    #[test]
+    fn block_between_snapshot_and_count() {
+        let node = Rc::new(RefCell::new(Node {
+            chain: MockChain::new(10),
+            mempool: vec![(txid(1), 0)],
+            wallet: vec![],
+            mined: vec![],
+            racing: vec![],
+        }));
+        let mut watcher = MempoolWatcher::new(client(&node));
+        assert_eq!(added(&watcher.poll().unwrap()), vec![txid(1)]);
+
+        // the snapshot still has the transaction, but the block count includes the block
+        // that mined it
+        node.borrow_mut().racing = vec![txid(1)];
+        assert!(watcher.poll().unwrap().is_empty());
+
+        let removed = removed(&watcher.poll().unwrap());
+        assert_eq!(removed[&txid(1)], RemovalReason::Mined);
+    }
+}
@@ -0,0 +1,161 @@
+//! A scripted [`RpcApi`] for the tests of the modules that drive a daemon.
+
+// not every test uses every helper
+#![allow(dead_code)]
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use serde_json::{json, Value};
+
+use crate::bitcoin::hashes::{sha256d, Hash};
+use crate::bitcoin::{BlockHash, Txid};
+use crate::{Error, Result, RpcApi};
+
+/// Answers every call with `handler` and records the calls.
+pub(crate) struct MockRpc {
+    handler: Box<dyn Fn(&str, &[Value]) -> Result<Value>>,
+    calls: RefCell<Vec<(String, Vec<Value>)>>,
+}
+
+impl MockRpc {
+    pub fn new<F>(handler: F) -> Self
+    where
+        F: Fn(&str, &[Value]) -> Result<Value> + 'static,
+    {
+        MockRpc {
+            handler: Box::new(handler),
+            calls: RefCell::new(vec![]),
+        }
+    }
+
+    /// The arguments of every call of `method`.
+    pub fn calls(&self, method: &str) -> Vec<Vec<Value>> {
+        self.calls
+            .borrow()
+            .iter()
+            .filter(|(m, _)| m == method)
+            .map(|(_, args)| args.clone())
+            .collect()
+    }
+}
+
+impl RpcApi for MockRpc {
+    fn call<T: for<'a> serde::de::Deserialize<'a>>(&self, cmd: &str, args: &[Value]) -> Result<T> {
+        self.calls
+            .borrow_mut()
+            .push((cmd.to_string(), args.to_vec()));
+        Ok(serde_json::from_value((self.handler)(cmd, args)?)?)
+    }
+}
+
+/// The error komodod returns with `code`.
+pub(crate) fn rpc_error(code: i32, message: &str) -> Error {
+    let error = serde_json::from_value(json!({ "code": code, "message": message }))
+        .expect("valid rpc error");
+    Error::JsonRPC(jsonrpc::Error::Rpc(error))
+}
+
+pub(crate) fn unexpected(cmd: &str) -> Result<Value> {
+    Err(Error::KMDError(format!("unexpected call {}", cmd)))
+}
+
+pub(crate) fn txid(n: u32) -> Txid {
+    Txid::from_hash(sha256d::Hash::hash(&n.to_le_bytes()))
+}
+
+/// A chain that can be extended and reorganized, answering `getblockcount`, `getblockhash` and
+/// `getblock`. Blocks that left the active chain can still be looked up by hash.
+pub(crate) struct MockChain {
+    active: Vec<BlockHash>,
+    blocks: HashMap<BlockHash, Value>,
+    created: u32,
+}
+
+impl MockChain {
+    /// A chain of `len` blocks without transactions, the genesis block at height 0.
+    pub fn new(len: usize) -> Self {
+        let mut chain = MockChain {
+            active: vec![],
+            blocks: HashMap::new(),
+            created: 0,
+        };
+        for _ in 0..len {
+            chain.push(&[]);
+        }
+        chain
+    }
+
+    pub fn push(&mut self, tx: &[Txid]) -> BlockHash {
+        self.created += 1;
+        let hash = BlockHash::from_hash(sha256d::Hash::hash(&self.created.to_be_bytes()));
+        let height = self.active.len();
+        let block = json!({
+            "last_notarized_height": 0,
+            "hash": hash,
+            "confirmations": 1,
+            "rawconfirmations": 1,
+            "size": 0,
+            "height": height,
+            "version": 4,
+            "merkleroot": "00".repeat(32),
+            "segid": -1,
+            "finalsaplingroot": "",
+            "tx": tx,
+            "time": 1_600_000_000 + height as u64 * 60,
+            "nonce": "",
+            "solution": "",
+            "bits": "",
+            "difficulty": 1.0,
+            "chainwork": "",
+            "anchor": "",
+            "blocktype": "mined",
+            "valuePools": [],
+            "previousblockhash": self.active.last(),
+            "nextblockhash": null,
+        });
+        self.blocks.insert(hash, block);
+        self.active.push(hash);
+        hash
+    }
+
+    /// Takes the top `depth` blocks off the active chain.
+    pub fn disconnect(&mut self, depth: usize) {
+        let len = self.active.len() - depth;
+        self.active.truncate(len);
+    }
+
+    pub fn hash(&self, height: usize) -> BlockHash {
+        self.active[height]
+    }
+
+    pub fn tip(&self) -> BlockHash {
+        *self.active.last().expect("chain isn't empty")
+    }
+
+    pub fn height(&self) -> u64 {
+        self.active.len() as u64 - 1
+    }
+
+    /// Answers the block calls, `None` for other calls.
+    pub fn handle(&self, cmd: &str, args: &[Value]) -> Option<Result<Value>> {
+        Some(match cmd {
+            "getblockcount" => Ok(json!(self.height())),
+            "getblockhash" => {
+                let height = args[0].as_u64().expect("height") as usize;
+                match self.active.get(height) {
+                    Some(hash) => Ok(json!(hash)),
+                    None => Err(rpc_error(-8, "Block height out of range")),
+                }
+            }
+            "getblock" => {
+                let hash: BlockHash = serde_json::from_value(args[0].clone()).expect("hash");
+                match self.blocks.get(&hash) {
+                    Some(block) => Ok(block.clone()),
+                    None => Err(rpc_error(-5, "Block not found")),
+                }
+            }
+            _ => return None,
+        })
+    }
+}
@@ -0,0 +1,312 @@
+//! M-of-N multisig workflow: create the address, build an unsigned spend, collect the partial
+//! signatures of the co-signers, combine them and broadcast the result.
+//!
+//! The co-signers sign the same unsigned transaction independently with
+//! [MultisigSpend::sign]. The coordinator combines their results with [MultisigSpend::combine],
+//! which reports how many signatures are still missing.
+
+use std::collections::HashMap;
+
+use crate::bitcoin::blockdata::opcodes;
+use crate::bitcoin::blockdata::script::{Builder, Instruction};
+use crate::bitcoin::hashes::hex::FromHex;
+use crate::bitcoin::{PublicKey, Script, Txid};
+use crate::json::komodo::util::amount::Amount;
+use crate::json::komodo::PrivateKey;
+use crate::json::{
+    CreateMultiSigResult, CreateRawTransactionInput, ListUnspentResult, PubkeyOrAddress,
+    SignRawTransactionInput, SignRawTransactionResult,
+};
+use crate::{Error, Result, RpcApi};
+
+/// Builds the redeem script of an M-of-N multisig, the same way komodod does: the keys are
+/// used in the order they are given.
+pub fn redeem_script(n_required: u8, pubkeys: &[PublicKey]) -> Result<Script> {
+    if n_required == 0 || n_required as usize > pubkeys.len() {
+        return Err(Error::KMDError(format!(
+            "{} signatures required, but {} keys given",
+            n_required,
+            pubkeys.len()
+        )));
+    }
+    if pubkeys.len() > 15 {
+        return Err(Error::KMDError(String::from(
+            "No more than 15 signers in a msig allowed",
+        )));
+    }
+
+    let builder = pubkeys.iter().fold(
+        Builder::new().push_int(n_required as i64),
+        |builder, key| builder.push_key(key),
+    );
+
+    Ok(builder
+        .push_int(pubkeys.len() as i64)
+        .push_opcode(opcodes::all::OP_CHECKMULTISIG)
+        .into_script())
+}
+
+/// Creates a multisig address with `createmultisig` and checks that the daemon's redeem script
+/// matches the one built locally.
+pub fn create_verified<C: RpcApi>(
+    client: &C,
+    n_required: u8,
+    pubkeys: &[PublicKey],
+) -> Result<CreateMultiSigResult> {
+    let script = redeem_script(n_required, pubkeys)?;
+    let keys: Vec<PubkeyOrAddress> = pubkeys.iter().cloned().map(PubkeyOrAddress::from).collect();
+    let multisig = client.create_multisig(n_required, &keys)?;
+
+    if multisig.redeem_script != script {
+        return Err(Error::KMDError(String::from(
+            "redeem script of the daemon does not match the local redeem script",
+        )));
+    }
+
+    Ok(multisig)
+}
+
+/// The number of signatures a multisig redeem script requires, `None` if it is not a multisig
+/// script.
+pub fn required_signatures(redeem_script: &Script) -> Option<u8> {
+    let last = redeem_script.as_bytes().last()?;
+    if *last != opcodes::all::OP_CHECKMULTISIG.into_u8() {
+        return None;
+    }
+
+    match redeem_script.instructions().next()? {
+        Ok(Instruction::Op(op)) => {
+            let op = op.into_u8();
+            let first = opcodes::all::OP_PUSHNUM_1.into_u8();
+            let last = opcodes::all::OP_PUSHNUM_16.into_u8();
+            if op >= first && op <= last {
+                Some(op - first + 1)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// The signatures in the scriptSig of a P2SH multisig input.
+///
+/// A multisig scriptSig is `OP_0 <sig>... <redeemScript>`; komodod fills the place of missing
+/// signatures with `OP_0` when it combines partial signatures.
+pub fn signatures(script_sig: &Script, redeem_script: &Script) -> Vec<Vec<u8>> {
+    let pushes: Vec<&[u8]> = script_sig
+        .instructions()
+        .filter_map(|instruction| match instruction {
+            Ok(Instruction::PushBytes(bytes)) => Some(bytes),
+            _ => None,
+        })
+        .collect();
+
+    match pushes.split_last() {
+        Some((last, sigs)) if *last == redeem_script.as_bytes() => sigs
+            .iter()
+            .filter(|sig| !sig.is_empty())
+            .map(|sig| sig.to_vec())
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// The number of signatures in the scriptSig of a P2SH multisig input.
+pub fn count_signatures(script_sig: &Script, redeem_script: &Script) -> usize {
+    signatures(script_sig, redeem_script).len()
+}
+
+/// An unsigned transaction that spends UTXOs of a multisig address.
+#[derive(Clone, Debug)]
+pub struct MultisigSpend {
+    pub n_required: u8,
+    pub redeem_script: Script,
+    /// The spent outputs, which every signer needs to sign.
+    pub prevouts: Vec<SignRawTransactionInput>,
+    pub unsigned_hex: String,
+}
+
+/// The result of combining partial signatures.
+#[derive(Clone, Debug)]
+pub struct CombinedSpend {
+    pub hex: String,
+    pub complete: bool,
+    /// The number of signatures of every input.
+    pub signatures: Vec<usize>,
+    /// The number of signatures that are still needed for the input with the fewest.
+    pub missing: usize,
+}
+
+impl MultisigSpend {
+    /// Creates the unsigned transaction that spends `utxos` of the multisig to `outputs`. The
+    /// difference between the two is the fee.
+    pub fn new<C: RpcApi>(
+        client: &C,
+        n_required: u8,
+        redeem_script: Script,
+        utxos: &[ListUnspentResult],
+        outputs: &HashMap<String, Amount>,
+    ) -> Result<Self> {
+        let p2sh = redeem_script.to_p2sh();
+        let mut inputs = vec![];
+        let mut prevouts = vec![];
+
+        for utxo in utxos {
+            if utxo.script_pub_key != p2sh {
+                return Err(Error::KMDError(format!(
+                    "{}:{} is not an output of the multisig",
+                    utxo.txid, utxo.vout
+                )));
+            }
+            inputs.push(CreateRawTransactionInput {
+                txid: utxo.txid,
+                vout: utxo.vout as u32,
+                sequence: None,
+            });
+            prevouts.push(SignRawTransactionInput {
+                txid: utxo.txid,
+                vout: utxo.vout as u32,
+                script_pub_key: utxo.script_pub_key.clone(),
+                redeem_script: Some(redeem_script.clone()),
+                amount: Some(utxo.amount.to_unsigned()?),
+            });
+        }
+
+        let unsigned_hex = client.createrawtransaction(&inputs, outputs, None, None)?;
+
+        Ok(MultisigSpend {
+            n_required,
+            redeem_script,
+            prevouts,
+            unsigned_hex,
+        })
+    }
+
+    /// Signs the unsigned transaction as one of the co-signers, with `privkeys` or with the
+    /// keys in the co-signer's wallet when `privkeys` is `None`.
+    pub fn sign<C: RpcApi>(
+        &self,
+        client: &C,
+        privkeys: Option<&[PrivateKey]>,
+    ) -> Result<SignRawTransactionResult> {
+        client.signrawtransaction(&self.unsigned_hex, Some(&self.prevouts), privkeys, None)
+    }
+
+    /// Merges the signatures of the partially signed transactions of the co-signers.
+    ///
+    /// The transactions are concatenated and passed to `signrawtransaction` without keys, which
+    /// makes the daemon only combine the signatures that are already there.
+    pub fn combine<C: RpcApi>(
+        &self,
+        client: &C,
+        partials: &[SignRawTransactionResult],
+    ) -> Result<CombinedSpend> {
+        if partials.is_empty() {
+            return Err(Error::KMDError(String::from(
+                "no partially signed transactions to combine",
+            )));
+        }
+
+        let concatenated: String = partials
+            .iter()
+            .map(|partial| partial.hex.as_str())
+            .collect();
+        let merged =
+            client.signrawtransaction(&concatenated, Some(&self.prevouts), Some(&[]), None)?;
+
+        let decoded = client.decoderawtransaction(&merged.hex)?;
+        let signatures = decoded
+            .vin
+            .iter()
+            .map(|vin| {
+                let script_sig: Vec<u8> = FromHex::from_hex(&vin.script_sig.hex)
+                    .map_err(|_| Error::KMDError(String::from("invalid scriptSig hex")))?;
+                Ok(count_signatures(
+                    &Script::from(script_sig),
+                    &self.redeem_script,
+                ))
+            })
+            .collect::<Result<Vec<usize>>>()?;
+
+        let fewest = signatures.iter().cloned().min().unwrap_or(0);
+
+        Ok(CombinedSpend {
+            hex: merged.hex,
+            complete: merged.complete,
+            signatures,
+            missing: (self.n_required as usize).saturating_sub(fewest),
+        })
+    }
+
+    /// Broadcasts a combined transaction that has all the signatures it needs.
+    pub fn finalize<C: RpcApi>(&self, client: &C, combined: &CombinedSpend) -> Result<Txid> {
+        if !combined.complete || combined.missing > 0 {
+            return Err(Error::KMDError(format!(
+                "{} more signature(s) needed",
+                combined.missing.max(1)
+            )));
+        }
+
+        client.sendrawtransaction(&combined.hex, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn pubkeys() -> Vec<PublicKey> {
+        [
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+            "02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5",
+            "02f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9",
+        ]
+        .iter()
+        .map(|key| PublicKey::from_str(key).unwrap())
+        .collect()
+    }
+
+    #[test]
+    fn two_of_three_redeem_script() {
+        let script = redeem_script(2, &pubkeys()).unwrap();
+        let bytes = script.as_bytes();
+
+        assert_eq!(bytes.len(), 1 + 3 * 34 + 1 + 1);
+        assert_eq!(bytes[0], opcodes::all::OP_PUSHNUM_2.into_u8());
+        assert_eq!(bytes[bytes.len() - 2], opcodes::all::OP_PUSHNUM_3.into_u8());
+        assert_eq!(
+            bytes[bytes.len() - 1],
+            opcodes::all::OP_CHECKMULTISIG.into_u8()
+        );
+
+        assert_eq!(required_signatures(&script), Some(2));
+        assert!(redeem_script(4, &pubkeys()).is_err());
+        assert!(redeem_script(0, &pubkeys()).is_err());
+    }
+
+    #[test]
+    fn count_partial_signatures() {
+        let redeem = redeem_script(2, &pubkeys()).unwrap();
+        let sig = [0x30u8; 71];
+
+        let partial = Builder::new()
+            .push_int(0)
+            .push_slice(&sig)
+            .push_int(0)
+            .push_slice(redeem.as_bytes())
+            .into_script();
+        assert_eq!(count_signatures(&partial, &redeem), 1);
+
+        let complete = Builder::new()
+            .push_int(0)
+            .push_slice(&sig)
+            .push_slice(&sig)
+            .push_slice(redeem.as_bytes())
+            .into_script();
+        assert_eq!(count_signatures(&complete, &redeem), 2);
+
+        assert_eq!(count_signatures(&Script::new(), &redeem), 0);
+    }
+}
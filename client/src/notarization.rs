@@ -0,0 +1,367 @@
+//! Recognising dPoW notarization transactions in blocks.
+//!
+//! Notaries notarize by jointly spending one of their UTXOs each into a transaction that pays the
+//! CRYPTO777 pubkey, followed by an OP_RETURN with the [`NotarizationData`]. Anyone can build a
+//! transaction of that shape, so a notarization is only recognised when at least
+//! [`MIN_NOTARY_SIGNERS`] of its inputs spend outputs of the notaries of the block.
+
+use crate::bitcoin::blockdata::opcodes;
+use crate::bitcoin::blockdata::script::{Builder, Instruction};
+use crate::bitcoin::hashes::hex::FromHex;
+use crate::bitcoin::{BlockHash, PublicKey, Script, Txid};
+use crate::json::{GetRawTransactionResultVerbose, MerkleOfMerkles, NotarizationData};
+use crate::{Result, RpcApi};
+
+/// The least number of notaries that sign a notarization.
+pub const MIN_NOTARY_SIGNERS: usize = 13;
+/// The pubkey notarizations pay to.
+pub const CRYPTO777_PUBKEY: &str =
+    "020e46e79a2a8d12b9b5d12c7a91adb4e454edfae43c0a0cb805427d2ac7613fd9";
+
+/// A notarization transaction and the data it commits to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Notarization {
+    pub txid: Txid,
+    /// The block that includes the notarization, `None` while it is in the mempool.
+    pub blockhash: Option<BlockHash>,
+    pub height: Option<u32>,
+    /// The notaries that signed, in input order.
+    pub signers: Vec<PublicKey>,
+    pub data: NotarizationData,
+}
+
+impl Notarization {
+    /// Returns `None` if the transaction isn't a notarization. `spent` holds the scriptPubKey hex
+    /// of the output each input spends, in input order, and `notaries` the notaries of the block
+    /// the transaction is in, for instance from a [`Season`](crate::notary_seasons::Season) or
+    /// the `notaries` RPC.
+    pub fn from_transaction(
+        tx: &GetRawTransactionResultVerbose,
+        spent: &[String],
+        notaries: &[PublicKey],
+    ) -> Option<Self> {
+        let data = decode(tx)?;
+
+        let mut signers: Vec<PublicKey> = vec![];
+        for script in spent {
+            let script = Vec::<u8>::from_hex(script).ok()?;
+            let signer = notaries.iter().find(|pubkey| p2pk_script(pubkey) == script);
+            if let Some(pubkey) = signer {
+                if !signers.contains(pubkey) {
+                    signers.push(*pubkey);
+                }
+            }
+        }
+        if signers.len() < MIN_NOTARY_SIGNERS {
+            return None;
+        }
+
+        Some(Notarization {
+            txid: tx.txid,
+            blockhash: tx.blockhash,
+            height: tx.height,
+            signers,
+            data,
+        })
+    }
+
+    /// Like [`from_transaction`](Notarization::from_transaction), but looks up the spent outputs,
+    /// which needs `-txindex`. Only transactions shaped like a notarization are looked up.
+    pub fn check_transaction<C: RpcApi>(
+        client: &C,
+        tx: &GetRawTransactionResultVerbose,
+        notaries: &[PublicKey],
+    ) -> Result<Option<Self>> {
+        if decode(tx).is_none() {
+            return Ok(None);
+        }
+
+        let mut spent = Vec::with_capacity(tx.vin.len());
+        for vin in &tx.vin {
+            let prev = client.get_raw_transaction_verbose(&vin.txid)?;
+            match prev.vout.into_iter().find(|vout| vout.n == vin.vout) {
+                Some(vout) => spent.push(vout.script_pubkey.hex),
+                None => return Ok(None),
+            }
+        }
+
+        Ok(Notarization::from_transaction(tx, &spent, notaries))
+    }
+
+    /// The hash of the notarized block.
+    pub fn prev_hash(&self) -> BlockHash {
+        self.data.block_hash
+    }
+
+    pub fn notarized_height(&self) -> u32 {
+        self.data.height
+    }
+
+    /// Symbol of the notarized chain.
+    pub fn symbol(&self) -> &str {
+        &self.data.symbol
+    }
+
+    pub fn mom(&self) -> Option<&MerkleOfMerkles> {
+        self.data.mom.as_ref()
+    }
+
+    pub fn ccid(&self) -> Option<u16> {
+        self.data.mom.map(|mom| mom.ccid)
+    }
+}
+
+// The shape of a notarization, without looking at who signed it.
+fn decode(tx: &GetRawTransactionResultVerbose) -> Option<NotarizationData> {
+    if tx.vin.len() < MIN_NOTARY_SIGNERS || tx.vout.len() < 2 {
+        return None;
+    }
+    if tx.vin.iter().any(|vin| vin.is_coinbase()) {
+        return None;
+    }
+    if Vec::<u8>::from_hex(&tx.vout[0].script_pubkey.hex).ok()? != crypto777_script() {
+        return None;
+    }
+    NotarizationData::decode(&opreturn_data(&tx.vout[1].script_pubkey.hex)?)
+}
+
+fn crypto777_script() -> Vec<u8> {
+    p2pk_script(&CRYPTO777_PUBKEY.parse().expect("valid pubkey"))
+}
+
+fn p2pk_script(pubkey: &PublicKey) -> Vec<u8> {
+    Builder::new()
+        .push_key(pubkey)
+        .push_opcode(opcodes::all::OP_CHECKSIG)
+        .into_script()
+        .into_bytes()
+}
+
+/// The data pushed by an OP_RETURN script.
+pub fn opreturn_data(script_hex: &str) -> Option<Vec<u8>> {
+    let script = Script::from(Vec::<u8>::from_hex(script_hex).ok()?);
+    if !script.is_op_return() {
+        return None;
+    }
+
+    let mut instructions = script.instructions().skip(1);
+    match instructions.next() {
+        Some(Ok(Instruction::PushBytes(data))) => Some(data.to_vec()),
+        _ => None,
+    }
+}
+
+/// The notarizations in a block. Fetches every transaction of the block, so it needs
+/// `-txindex`.
+pub fn notarizations_in_block<C: RpcApi>(
+    client: &C,
+    hash: &BlockHash,
+) -> Result<Vec<Notarization>> {
+    let block = client.get_block(hash)?;
+    // the coinbase is never a notarization
+    let txs = block
+        .tx
+        .iter()
+        .skip(1)
+        .map(|txid| client.get_raw_transaction_verbose(txid))
+        .collect::<Result<Vec<_>>>()?;
+
+    find_notarizations(client, block.height as u64, block.time, &txs)
+}
+
+/// The notarizations among `txs` of the block at `height` and `time`. The notaries of the block
+/// are only looked up when a transaction is shaped like a notarization.
+pub fn find_notarizations<C: RpcApi>(
+    client: &C,
+    height: u64,
+    time: u64,
+    txs: &[GetRawTransactionResultVerbose],
+) -> Result<Vec<Notarization>> {
+    let mut notaries: Option<Vec<PublicKey>> = None;
+    let mut notarizations = vec![];
+
+    for tx in txs.iter().filter(|tx| decode(tx).is_some()) {
+        if notaries.is_none() {
+            let pubkeys = client
+                .notaries(height, Some(time))?
+                .notaries
+                .iter()
+                .map(|n| n.pubkey)
+                .collect();
+            notaries = Some(pubkeys);
+        }
+        let notaries = notaries.as_ref().expect("notaries were just looked up");
+        if let Some(notarization) = Notarization::check_transaction(client, tx, notaries)? {
+            notarizations.push(notarization);
+        }
+    }
+
+    Ok(notarizations)
+}
+
+/// The notarizations decoded from a block next to the ones the daemon reports for it.
+#[derive(Clone, Debug)]
+pub struct NotarizationCrossCheck {
+    pub height: u64,
+    pub decoded: Vec<Notarization>,
+    /// Decoded notarizations the daemon doesn't report.
+    pub only_decoded: Vec<Txid>,
+    /// Notarizations the daemon reports that weren't decoded.
+    pub only_reported: Vec<Txid>,
+}
+
+impl NotarizationCrossCheck {
+    pub fn is_consistent(&self) -> bool {
+        self.only_decoded.is_empty() && self.only_reported.is_empty()
+    }
+}
+
+/// Compares the notarizations decoded from the block at `height` with `getNotarisationsForBlock`.
+pub fn cross_check<C: RpcApi>(client: &C, height: u64) -> Result<NotarizationCrossCheck> {
+    let hash = client.get_block_hash(height)?;
+    let decoded = notarizations_in_block(client, &hash)?;
+    let reported = client.get_notarisations_for_block(height)?;
+    let reported: Vec<Txid> = reported
+        .kmd
+        .iter()
+        .chain(reported.labs.iter())
+        .map(|n| n.txid)
+        .collect();
+
+    let only_decoded = decoded
+        .iter()
+        .map(|n| n.txid)
+        .filter(|txid| !reported.contains(txid))
+        .collect();
+    let only_reported = reported
+        .iter()
+        .filter(|txid| !decoded.iter().any(|n| n.txid == **txid))
+        .copied()
+        .collect();
+
+    Ok(NotarizationCrossCheck {
+        height,
+        decoded,
+        only_decoded,
+        only_reported,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin::hashes::hex::ToHex;
+    use crate::bitcoin::secp256k1::{self, Secp256k1, SecretKey};
+
+    fn transaction(vins: usize, opreturn: &[u8]) -> GetRawTransactionResultVerbose {
+        let vin = format!(
+            r#"{{"txid": "{}", "vout": 0, "scriptSig": {{"asm": "", "hex": ""}}, "sequence": 4294967295}}"#,
+            "11".repeat(32)
+        );
+        let opreturn = Builder::new()
+            .push_opcode(opcodes::all::OP_RETURN)
+            .push_slice(opreturn)
+            .into_script();
+
+        serde_json::from_str(&format!(
+            r#"{{
+                "hex": "",
+                "txid": "{txid}",
+                "version": 1,
+                "locktime": 0,
+                "expiryheight": 0,
+                "vin": [{vins}],
+                "vout": [
+                    {{"value": 0.00001, "n": 0, "scriptPubKey": {{"asm": "", "hex": "{p2pk}", "type": "pubkey"}}}},
+                    {{"value": 0, "n": 1, "scriptPubKey": {{"asm": "", "hex": "{opreturn}", "type": "nulldata"}}}}
+                ],
+                "vjoinsplit": []
+            }}"#,
+            txid = "22".repeat(32),
+            vins = vec![vin; vins].join(","),
+            p2pk = crypto777_script().to_hex(),
+            opreturn = opreturn.to_hex(),
+        ))
+        .unwrap()
+    }
+
+    fn pubkeys(seeds: std::ops::Range<u8>) -> Vec<PublicKey> {
+        let secp = Secp256k1::new();
+        seeds
+            .map(|seed| PublicKey {
+                compressed: true,
+                key: secp256k1::PublicKey::from_secret_key(
+                    &secp,
+                    &SecretKey::from_slice(&[seed; 32]).unwrap(),
+                ),
+            })
+            .collect()
+    }
+
+    fn spending(pubkeys: &[PublicKey]) -> Vec<String> {
+        pubkeys.iter().map(|pk| p2pk_script(pk).to_hex()).collect()
+    }
+
+    #[test]
+    fn decode_notarization() {
+        let data = NotarizationData {
+            block_hash: BlockHash::default(),
+            height: 1_000_000,
+            dest_txid: None,
+            symbol: String::from("DOC"),
+            mom: Some(MerkleOfMerkles {
+                root: Default::default(),
+                depth: 5,
+                ccid: 2,
+            }),
+            momom: None,
+        };
+        let notaries = pubkeys(1..20);
+        let tx = transaction(13, &data.encode());
+
+        let notarization =
+            Notarization::from_transaction(&tx, &spending(&notaries[..13]), &notaries).unwrap();
+        assert_eq!(notarization.symbol(), "DOC");
+        assert_eq!(notarization.notarized_height(), 1_000_000);
+        assert_eq!(notarization.ccid(), Some(2));
+        assert_eq!(notarization.signers, notaries[..13].to_vec());
+
+        let tx = transaction(12, &data.encode());
+        assert!(
+            Notarization::from_transaction(&tx, &spending(&notaries[..12]), &notaries).is_none()
+        );
+        let tx = transaction(13, b"not a notarization");
+        assert!(
+            Notarization::from_transaction(&tx, &spending(&notaries[..13]), &notaries).is_none()
+        );
+    }
+
+    #[test]
+    fn notarizations_need_notary_inputs() {
+        let data = NotarizationData {
+            block_hash: BlockHash::default(),
+            height: 1_000_000,
+            dest_txid: None,
+            symbol: String::from("DOC"),
+            mom: None,
+            momom: None,
+        };
+        let notaries = pubkeys(1..20);
+        let others = pubkeys(100..113);
+        let tx = transaction(13, &data.encode());
+
+        assert!(Notarization::from_transaction(&tx, &spending(&others), &notaries).is_none());
+
+        // the same notary twice counts once
+        let mut twice = notaries[..12].to_vec();
+        twice.push(notaries[0]);
+        assert!(Notarization::from_transaction(&tx, &spending(&twice), &notaries).is_none());
+
+        // 13 notaries among more inputs are enough
+        let tx = transaction(15, &data.encode());
+        let mut mixed = spending(&notaries[..13]);
+        mixed.extend(spending(&others[..2]));
+        let notarization = Notarization::from_transaction(&tx, &mixed, &notaries).unwrap();
+        assert_eq!(notarization.signers.len(), 13);
+    }
+}
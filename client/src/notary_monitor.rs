@@ -0,0 +1,508 @@
+//! Monitoring of notary nodes: blocks they mined, notarizations they signed, and which of them
+//! fell silent.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::bitcoin::PublicKey;
+use crate::json::{Notaries, Notary};
+use crate::{Result, RpcApi};
+
+/// Number of blocks `minerids` tallies, ending at the requested height.
+pub const MINERIDS_WINDOW: u64 = 2000;
+
+/// Blocks mined per notary. Counted in whole `minerids` windows, so `start` can be lower than
+/// the start of the requested range.
+#[derive(Clone, Debug, Default)]
+pub struct MinedTally {
+    pub start: u64,
+    pub end: u64,
+    pub blocks: HashMap<PublicKey, u32>,
+    /// Blocks mined by anyone that isn't a notary.
+    pub external: u32,
+}
+
+impl MinedTally {
+    pub fn blocks(&self, pubkey: &PublicKey) -> u32 {
+        self.blocks.get(pubkey).copied().unwrap_or(0)
+    }
+}
+
+/// Notarizations signed per notary. Only notarizations by the KMD notaries are tallied.
+#[derive(Clone, Debug, Default)]
+pub struct NotarizationTally {
+    pub start: u64,
+    pub end: u64,
+    pub signed: HashMap<PublicKey, u32>,
+    /// Height of the last block with a notarization signed by the notary.
+    pub last_signed: HashMap<PublicKey, u64>,
+    /// Number of KMD notarizations in the range.
+    pub notarizations: u32,
+    /// Number of notarizations by the LABS notaries in the range, which aren't credited to
+    /// anyone.
+    pub labs_notarizations: u32,
+}
+
+impl NotarizationTally {
+    pub fn signed(&self, pubkey: &PublicKey) -> u32 {
+        self.signed.get(pubkey).copied().unwrap_or(0)
+    }
+}
+
+/// Notaries by pubkey, collected over one or more seasons.
+#[derive(Clone, Debug, Default)]
+pub struct NotaryDirectory {
+    entries: HashMap<PublicKey, DirectoryEntry>,
+}
+
+#[derive(Clone, Debug)]
+pub struct DirectoryEntry {
+    pub notary: Notary,
+    /// Lowest and highest height the notary was seen at.
+    pub first_seen: u64,
+    pub last_seen: u64,
+}
+
+impl NotaryDirectory {
+    pub fn add(&mut self, notaries: &Notaries) {
+        let height = notaries.height as u64;
+        for notary in &notaries.notaries {
+            let entry = self
+                .entries
+                .entry(notary.pubkey)
+                .or_insert_with(|| DirectoryEntry {
+                    notary: notary.clone(),
+                    first_seen: height,
+                    last_seen: height,
+                });
+            entry.first_seen = entry.first_seen.min(height);
+            entry.last_seen = entry.last_seen.max(height);
+        }
+    }
+
+    pub fn get(&self, pubkey: &PublicKey) -> Option<&DirectoryEntry> {
+        self.entries.get(pubkey)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &DirectoryEntry> {
+        self.entries.values()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct NotaryActivity {
+    pub notary: Notary,
+    pub mined: u32,
+    pub notarizations: u32,
+    pub last_notarization: Option<u64>,
+    pub stopped_mining: bool,
+    pub stopped_notarizing: bool,
+}
+
+/// Notarizations are counted over `start..=end`, mined blocks over `mined_start..=end`.
+#[derive(Clone, Debug)]
+pub struct NotaryReport {
+    pub start: u64,
+    /// The start of the `minerids` windows, at or below `start`.
+    pub mined_start: u64,
+    pub end: u64,
+    /// The notaries at `end`, in notary id order.
+    pub activity: Vec<NotaryActivity>,
+    pub external_mined: u32,
+    pub notarizations: u32,
+}
+
+impl NotaryReport {
+    fn new(
+        notaries: &Notaries,
+        mined: &MinedTally,
+        notarizations: &NotarizationTally,
+        min_mined: u32,
+        min_notarizations: u32,
+    ) -> Self {
+        let activity = notaries
+            .notaries
+            .iter()
+            .map(|notary| {
+                let blocks = mined.blocks(&notary.pubkey);
+                let signed = notarizations.signed(&notary.pubkey);
+                NotaryActivity {
+                    notary: notary.clone(),
+                    mined: blocks,
+                    notarizations: signed,
+                    last_notarization: notarizations.last_signed.get(&notary.pubkey).copied(),
+                    stopped_mining: blocks < min_mined,
+                    stopped_notarizing: signed < min_notarizations,
+                }
+            })
+            .collect();
+
+        NotaryReport {
+            start: notarizations.start,
+            mined_start: mined.start,
+            end: notarizations.end,
+            activity,
+            external_mined: mined.external,
+            notarizations: notarizations.notarizations,
+        }
+    }
+
+    pub fn stopped_mining(&self) -> impl Iterator<Item = &NotaryActivity> {
+        self.activity.iter().filter(|a| a.stopped_mining)
+    }
+
+    pub fn stopped_notarizing(&self) -> impl Iterator<Item = &NotaryActivity> {
+        self.activity.iter().filter(|a| a.stopped_notarizing)
+    }
+}
+
+impl fmt::Display for NotaryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "notary activity for blocks {} to {}: {} notarizations, \
+             {} blocks mined by others since block {}",
+            self.start, self.end, self.notarizations, self.external_mined, self.mined_start
+        )?;
+        for (id, activity) in self.activity.iter().enumerate() {
+            let last = activity
+                .last_notarization
+                .map_or_else(|| String::from("-"), |height| height.to_string());
+            write!(
+                f,
+                "{:>2} {} {:<34} mined {:>5} notarized {:>5} last {:>8}",
+                id,
+                activity.notary.pubkey,
+                activity.notary.kmd_address.to_string(),
+                activity.mined,
+                activity.notarizations,
+                last
+            )?;
+            if activity.stopped_mining {
+                write!(f, " [not mining]")?;
+            }
+            if activity.stopped_notarizing {
+                write!(f, " [not notarizing]")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Collects notary activity. A notary is reported as stopped when it signed less than the
+/// minimum in the range, or mined less than the minimum in the `minerids` windows covering it;
+/// both minimums default to 1.
+pub struct NotaryMonitor<'a, C: RpcApi> {
+    client: &'a C,
+    min_mined: u32,
+    min_notarizations: u32,
+}
+
+impl<'a, C: RpcApi> NotaryMonitor<'a, C> {
+    pub fn new(client: &'a C) -> Self {
+        NotaryMonitor {
+            client,
+            min_mined: 1,
+            min_notarizations: 1,
+        }
+    }
+
+    pub fn min_mined(mut self, blocks: u32) -> Self {
+        self.min_mined = blocks;
+        self
+    }
+
+    pub fn min_notarizations(mut self, notarizations: u32) -> Self {
+        self.min_notarizations = notarizations;
+        self
+    }
+
+    /// The notaries at `height`, using the time of the block so that asset chains return the
+    /// notaries of the season the block is in.
+    pub fn notaries_at(&self, height: u64) -> Result<Notaries> {
+        let hash = self.client.get_block_hash(height)?;
+        let time = self.client.get_block(&hash)?.time;
+        self.client.notaries(height, Some(time))
+    }
+
+    /// Blocks mined per notary in the `minerids` windows covering `start..=end`.
+    pub fn mined(&self, start: u64, end: u64) -> Result<MinedTally> {
+        let mut tally = MinedTally {
+            start: end,
+            end,
+            ..Default::default()
+        };
+
+        let mut window_end = end;
+        loop {
+            for miner in self.client.miner_ids(window_end)?.mined {
+                match miner.pubkey.parse::<PublicKey>() {
+                    Ok(pubkey) => *tally.blocks.entry(pubkey).or_insert(0) += miner.blocks,
+                    Err(_) => tally.external += miner.blocks,
+                }
+            }
+
+            let window_start = window_end.saturating_sub(MINERIDS_WINDOW - 1).max(1);
+            tally.start = window_start;
+            if window_start <= start.max(1) {
+                break;
+            }
+            window_end = window_start - 1;
+        }
+
+        Ok(tally)
+    }
+
+    /// Notarizations signed per notary in the blocks `start..=end`. Queries every block in the
+    /// range.
+    pub fn notarizations(&self, start: u64, end: u64) -> Result<NotarizationTally> {
+        let mut tally = NotarizationTally {
+            start,
+            end,
+            ..Default::default()
+        };
+
+        // notary ids are indexes into the notary list of the block, which only changes at
+        // season boundaries
+        let first = self.notaries_at(start)?;
+        let last = self.notaries_at(end)?;
+        let same_season = pubkeys(&first) == pubkeys(&last);
+
+        for height in start..=end {
+            let block = self.client.get_notarisations_for_block(height)?;
+            // LABS notary ids index the LABS notary set, which `notaries` doesn't return
+            tally.labs_notarizations += block.labs.len() as u32;
+            if block.kmd.is_empty() {
+                continue;
+            }
+
+            let fetched;
+            let notaries = if same_season {
+                &last
+            } else {
+                fetched = self.notaries_at(height)?;
+                &fetched
+            };
+
+            for notarization in &block.kmd {
+                tally.notarizations += 1;
+                for id in &notarization.notaries {
+                    if let Some(notary) = notaries.notaries.get(*id as usize) {
+                        *tally.signed.entry(notary.pubkey).or_insert(0) += 1;
+                        tally.last_signed.insert(notary.pubkey, height);
+                    }
+                }
+            }
+        }
+
+        Ok(tally)
+    }
+
+    /// The notaries of all seasons the given heights are in.
+    pub fn directory(&self, heights: &[u64]) -> Result<NotaryDirectory> {
+        let mut directory = NotaryDirectory::default();
+        for height in heights {
+            directory.add(&self.notaries_at(*height)?);
+        }
+        Ok(directory)
+    }
+
+    /// Mining and notarization activity of the notaries at `end` over `start..=end`.
+    pub fn report(&self, start: u64, end: u64) -> Result<NotaryReport> {
+        let notaries = self.notaries_at(end)?;
+        let mined = self.mined(start, end)?;
+        let notarizations = self.notarizations(start, end)?;
+
+        Ok(NotaryReport::new(
+            &notaries,
+            &mined,
+            &notarizations,
+            self.min_mined,
+            self.min_notarizations,
+        ))
+    }
+}
+
+fn pubkeys(notaries: &Notaries) -> Vec<PublicKey> {
+    notaries.notaries.iter().map(|n| n.pubkey).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::mock::{unexpected, MockChain, MockRpc};
+
+    const A: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    const B: &str = "02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5";
+    const C: &str = "02f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9";
+    // the first block of the second season
+    const BOUNDARY: u64 = 150;
+
+    fn season_notaries(height: u64) -> Value {
+        let pubkeys = if height < BOUNDARY { [A, B] } else { [B, C] };
+        let notaries: Vec<Value> = pubkeys
+            .iter()
+            .map(|pubkey| {
+                json!({
+                    "pubkey": pubkey,
+                    "BTCaddress": "1cMh228HTCiwS8ZsaakH8A8wze1JR5ZsP",
+                    "KMDaddress": "R9tYmXuQtH1J1SVmLkZsNeVLiG6by6fLs9"
+                })
+            })
+            .collect();
+        json!({
+            "notaries": notaries,
+            "numnotaries": 2,
+            "height": height,
+            "timestamp": 0
+        })
+    }
+
+    fn notarization(n: u8, notaries: &[u8]) -> Value {
+        json!({
+            "txid": format!("{:02x}", n).repeat(32),
+            "chain": "DOC",
+            "height": 100,
+            "blockhash": "00".repeat(32),
+            "notaries": notaries
+        })
+    }
+
+    fn client() -> MockRpc {
+        let chain = MockChain::new(200);
+        MockRpc::new(move |cmd, args| {
+            if let Some(result) = chain.handle(cmd, args) {
+                return result;
+            }
+            let height = || u64::from_str(args[0].as_str().unwrap()).unwrap();
+            match cmd {
+                "notaries" => Ok(season_notaries(height())),
+                "getNotarisationsForBlock" => Ok(match height() {
+                    // signed by A and B
+                    120 => json!({ "KMD": [notarization(1, &[0, 1])] }),
+                    // signed by B and C, and a LABS notarization by LABS notary 0
+                    180 => json!({
+                        "KMD": [notarization(2, &[0, 1])],
+                        "LABS": [notarization(3, &[0])]
+                    }),
+                    _ => json!({}),
+                }),
+                "minerids" => {
+                    let mined =
+                        |pubkey: &str, blocks: u32| json!({ "pubkey": pubkey, "blocks": blocks });
+                    Ok(match height() {
+                        4500 => json!({
+                            "mined": [mined(A, 5), mined(C, 3), mined("external miners", 2)],
+                            "numnotaries": 2
+                        }),
+                        2500 => json!({
+                            "mined": [mined(A, 4), mined(B, 6), mined("external miners", 1)],
+                            "numnotaries": 2
+                        }),
+                        _ => return unexpected(cmd),
+                    })
+                }
+                _ => unexpected(cmd),
+            }
+        })
+    }
+
+    #[test]
+    fn tallies_across_seasons() {
+        let client = client();
+        let monitor = NotaryMonitor::new(&client);
+        let (a, b, c) = (A.parse().unwrap(), B.parse().unwrap(), C.parse().unwrap());
+
+        let tally = monitor.notarizations(100, 199).unwrap();
+        assert_eq!(tally.notarizations, 2);
+        assert_eq!(tally.labs_notarizations, 1);
+        assert_eq!(tally.signed(&a), 1);
+        assert_eq!(tally.signed(&b), 2);
+        assert_eq!(tally.signed(&c), 1);
+        assert_eq!(tally.last_signed[&a], 120);
+        assert_eq!(tally.last_signed[&b], 180);
+
+        // both windows, with the notaries of both seasons
+        let mined = monitor.mined(1500, 4500).unwrap();
+        assert_eq!((mined.start, mined.end), (501, 4500));
+        assert_eq!(mined.blocks(&a), 9);
+        assert_eq!(mined.blocks(&b), 6);
+        assert_eq!(mined.blocks(&c), 3);
+        assert_eq!(mined.external, 3);
+        assert_eq!(client.calls("minerids").len(), 2);
+    }
+
+    fn notaries() -> Notaries {
+        serde_json::from_str(
+            r#"{
+                "notaries": [
+                    {
+                        "pubkey": "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+                        "BTCaddress": "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH",
+                        "KMDaddress": "RKxTdfmtxtfLDKZBgx6SvNkBtNu9jRYnLh"
+                    },
+                    {
+                        "pubkey": "02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5",
+                        "BTCaddress": "1cMh228HTCiwS8ZsaakH8A8wze1JR5ZsP",
+                        "KMDaddress": "R9tYmXuQtH1J1SVmLkZsNeVLiG6by6fLs9"
+                    }
+                ],
+                "numnotaries": 2,
+                "height": 100,
+                "timestamp": 1600000000
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn report_flags_inactive_notaries() {
+        let notaries = notaries();
+        let active = notaries.notaries[0].pubkey;
+        let silent = notaries.notaries[1].pubkey;
+
+        let mut mined = MinedTally {
+            start: 1,
+            end: 100,
+            ..Default::default()
+        };
+        mined.blocks.insert(active, 12);
+        mined.blocks.insert(silent, 0);
+        mined.external = 3;
+
+        let mut notarizations = NotarizationTally {
+            start: 90,
+            end: 100,
+            notarizations: 2,
+            ..Default::default()
+        };
+        notarizations.signed.insert(active, 2);
+        notarizations.last_signed.insert(active, 98);
+
+        let report = NotaryReport::new(&notaries, &mined, &notarizations, 1, 1);
+        let stopped_mining: Vec<_> = report.stopped_mining().map(|a| a.notary.pubkey).collect();
+        assert_eq!(stopped_mining, vec![silent]);
+        assert_eq!(report.stopped_notarizing().count(), 1);
+        assert_eq!(report.activity[0].last_notarization, Some(98));
+
+        let mut directory = NotaryDirectory::default();
+        directory.add(&notaries);
+        assert_eq!(directory.get(&silent).unwrap().first_seen, 100);
+        assert_eq!(report.mined_start, 1);
+        assert!(report.to_string().contains("since block 1\n"));
+        assert!(report.to_string().contains("[not notarizing]"));
+    }
+}
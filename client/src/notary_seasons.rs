@@ -0,0 +1,428 @@
+//! The notary seasons of the Komodo network.
+//!
+//! Notary pubkeys rotate at fixed boundaries. KMD switches seasons by block height, asset chains
+//! by block time. The built-in table holds those boundaries, numbered like komodod does, and the
+//! elected notaries of each season from `notaries_elected.json`, which follows the layout of
+//! komodod's `notaries_elected`: one list of `[name, pubkey]` pairs per season, in notary id
+//! order. [`SeasonTable::cross_check`] verifies the built-in notaries against the `notaries` RPC,
+//! and [`SeasonTable::update`] fills in seasons the built-in list doesn't cover yet. The table can
+//! be saved as JSON to classify notarizations offline.
+
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::bitcoin::PublicKey;
+use crate::json::Notaries;
+use crate::{Error, Result, RpcApi};
+
+/// The last KMD height and asset chain block time of each season, as in komodod.
+const KMD_SEASON_ENDS: [(u64, u64); 9] = [
+    (814_000, 1_525_132_800),
+    (1_444_000, 1_563_148_800),
+    (1_921_000, 1_592_146_800),
+    (2_437_300, 1_617_364_800),
+    (2_526_000, 1_623_682_800),
+    (2_963_330, 1_656_077_853),
+    (3_484_958, 1_688_132_253),
+    (4_125_988, 1_730_271_600),
+    (8_113_400, 1_951_328_000),
+];
+
+/// The elected notaries per season, see the module documentation.
+const NOTARIES_ELECTED: &str = include_str!("notaries_elected.json");
+
+/// How a chain switches seasons.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Schedule {
+    /// By block height, like KMD.
+    Height,
+    /// By block time, like asset chains.
+    Time,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SeasonNotary {
+    pub name: Option<String>,
+    pub pubkey: PublicKey,
+}
+
+/// A notary season. Both ranges are inclusive.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Season {
+    pub number: u8,
+    pub start_height: u64,
+    pub end_height: u64,
+    pub start_time: u64,
+    pub end_time: u64,
+    /// In notary id order. Empty for seasons the built-in list doesn't cover, until the table
+    /// is updated from a node or loaded from a file.
+    pub notaries: Vec<SeasonNotary>,
+}
+
+impl Season {
+    pub fn contains_height(&self, height: u64) -> bool {
+        self.start_height <= height && height <= self.end_height
+    }
+
+    pub fn contains_time(&self, time: u64) -> bool {
+        self.start_time <= time && time <= self.end_time
+    }
+
+    pub fn is_notary(&self, pubkey: &PublicKey) -> bool {
+        self.notaries.iter().any(|n| n.pubkey == *pubkey)
+    }
+
+    /// The notary id of `pubkey`, as used in notarization results.
+    pub fn notary_id(&self, pubkey: &PublicKey) -> Option<usize> {
+        self.notaries.iter().position(|n| n.pubkey == *pubkey)
+    }
+
+    /// Compares the notaries in this season with those the node returned.
+    pub fn cross_check(&self, notaries: &Notaries) -> CrossCheck {
+        let missing = notaries
+            .notaries
+            .iter()
+            .map(|n| n.pubkey)
+            .filter(|pubkey| !self.is_notary(pubkey))
+            .collect();
+        let unexpected = self
+            .notaries
+            .iter()
+            .map(|n| n.pubkey)
+            .filter(|pubkey| !notaries.notaries.iter().any(|n| n.pubkey == *pubkey))
+            .collect();
+
+        CrossCheck {
+            season: self.number,
+            missing,
+            unexpected,
+        }
+    }
+}
+
+/// The differences between a season in the table and the notaries a node returned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CrossCheck {
+    pub season: u8,
+    /// Notaries the node knows that the table doesn't.
+    pub missing: Vec<PublicKey>,
+    /// Notaries in the table that the node doesn't know.
+    pub unexpected: Vec<PublicKey>,
+}
+
+impl CrossCheck {
+    pub fn is_consistent(&self) -> bool {
+        self.missing.is_empty() && self.unexpected.is_empty()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SeasonTable {
+    seasons: Vec<Season>,
+}
+
+impl Default for SeasonTable {
+    fn default() -> Self {
+        SeasonTable::kmd()
+    }
+}
+
+impl SeasonTable {
+    /// The built-in season boundaries and elected notaries.
+    pub fn kmd() -> Self {
+        let mut elected = elected_notaries().into_iter();
+        let mut seasons = Vec::with_capacity(KMD_SEASON_ENDS.len());
+        let (mut start_height, mut start_time) = (0, 0);
+        for (i, (end_height, end_time)) in KMD_SEASON_ENDS.iter().enumerate() {
+            seasons.push(Season {
+                number: i as u8 + 1,
+                start_height,
+                end_height: *end_height,
+                start_time,
+                end_time: *end_time,
+                notaries: elected.next().unwrap_or_default(),
+            });
+            start_height = end_height + 1;
+            start_time = end_time + 1;
+        }
+
+        SeasonTable { seasons }
+    }
+
+    pub fn seasons(&self) -> &[Season] {
+        &self.seasons
+    }
+
+    pub fn season(&self, number: u8) -> Option<&Season> {
+        self.seasons.iter().find(|s| s.number == number)
+    }
+
+    /// Adds a season, or replaces the one with the same number. Seasons are kept in order.
+    pub fn insert(&mut self, season: Season) {
+        self.seasons.retain(|s| s.number != season.number);
+        self.seasons.push(season);
+        self.seasons.sort_by_key(|s| s.number);
+    }
+
+    /// The season of a KMD block.
+    pub fn season_at_height(&self, height: u64) -> Option<&Season> {
+        self.seasons.iter().find(|s| s.contains_height(height))
+    }
+
+    /// The season of an asset chain block.
+    pub fn season_at_time(&self, time: u64) -> Option<&Season> {
+        self.seasons.iter().find(|s| s.contains_time(time))
+    }
+
+    pub fn current(&self) -> Option<&Season> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.season_at_time(now)
+    }
+
+    /// Whether `pubkey` is a notary of the season of KMD block `height`.
+    pub fn is_notary_at_height(&self, pubkey: &PublicKey, height: u64) -> bool {
+        self.season_at_height(height)
+            .map_or(false, |s| s.is_notary(pubkey))
+    }
+
+    /// Whether `pubkey` is a notary of the season of an asset chain block at `time`.
+    pub fn is_notary_at_time(&self, pubkey: &PublicKey, time: u64) -> bool {
+        self.season_at_time(time)
+            .map_or(false, |s| s.is_notary(pubkey))
+    }
+
+    /// The season of a block on a chain with the given schedule.
+    pub fn season_for(&self, schedule: Schedule, height: u64, time: u64) -> Option<&Season> {
+        match schedule {
+            Schedule::Height => self.season_at_height(height),
+            Schedule::Time => self.season_at_time(time),
+        }
+    }
+
+    /// Compares the season of the block at `height` and `time` with the notaries the node
+    /// returns. A season without notaries is filled in from the node, without names; the
+    /// notaries of other seasons are kept, and the returned check shows where they differ from
+    /// the node.
+    pub fn update<C: RpcApi>(
+        &mut self,
+        client: &C,
+        schedule: Schedule,
+        height: u64,
+        time: u64,
+    ) -> Result<CrossCheck> {
+        let number = self
+            .season_for(schedule, height, time)
+            .ok_or_else(|| no_season(height, time))?
+            .number;
+        let notaries = client.notaries(height, Some(time))?;
+
+        let season = self
+            .seasons
+            .iter_mut()
+            .find(|s| s.number == number)
+            .expect("season was just found");
+        let check = season.cross_check(&notaries);
+        if season.notaries.is_empty() {
+            season.notaries = notaries
+                .notaries
+                .iter()
+                .map(|n| SeasonNotary {
+                    name: None,
+                    pubkey: n.pubkey,
+                })
+                .collect();
+        }
+
+        Ok(check)
+    }
+
+    /// Compares the table with the notaries the node returns for the block at `height` and
+    /// `time`.
+    pub fn cross_check<C: RpcApi>(
+        &self,
+        client: &C,
+        schedule: Schedule,
+        height: u64,
+        time: u64,
+    ) -> Result<CrossCheck> {
+        let season = self
+            .season_for(schedule, height, time)
+            .ok_or_else(|| no_season(height, time))?;
+        let notaries = client.notaries(height, Some(time))?;
+
+        Ok(season.cross_check(&notaries))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let json = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+fn elected_notaries() -> Vec<Vec<SeasonNotary>> {
+    let seasons: Vec<Vec<(String, String)>> =
+        serde_json::from_str(NOTARIES_ELECTED).expect("valid built-in notary list");
+    seasons
+        .into_iter()
+        .map(|notaries| {
+            notaries
+                .into_iter()
+                .map(|(name, pubkey)| SeasonNotary {
+                    name: Some(name),
+                    pubkey: pubkey.parse().expect("valid built-in notary pubkey"),
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn no_season(height: u64, time: u64) -> Error {
+    Error::KMDError(format!(
+        "no notary season for height {} at time {}",
+        height, time
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{unexpected, MockRpc};
+    use serde_json::json;
+    use std::collections::HashSet;
+
+    const ALICE: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    const BOB: &str = "02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5";
+
+    #[test]
+    fn built_in_notaries() {
+        for season in SeasonTable::kmd().seasons() {
+            assert!(season.notaries.len() <= 64, "season {}", season.number);
+            let pubkeys: HashSet<_> = season.notaries.iter().map(|n| n.pubkey).collect();
+            assert_eq!(
+                pubkeys.len(),
+                season.notaries.len(),
+                "season {}",
+                season.number
+            );
+            assert!(season
+                .notaries
+                .iter()
+                .all(|n| n.name.as_ref().map_or(false, |name| !name.is_empty())));
+        }
+    }
+
+    #[test]
+    fn season_boundaries() {
+        let table = SeasonTable::kmd();
+        assert_eq!(elected_notaries().len(), KMD_SEASON_ENDS.len());
+        assert_eq!(table.season_at_height(0).unwrap().number, 1);
+        assert_eq!(table.season_at_time(0).unwrap().number, 1);
+
+        for (i, (end_height, end_time)) in KMD_SEASON_ENDS.iter().enumerate() {
+            let number = i as u8 + 1;
+            let number_of = |season: Option<&Season>| season.map(|s| s.number);
+            assert_eq!(number_of(table.season_at_height(*end_height)), Some(number));
+            assert_eq!(number_of(table.season_at_time(*end_time)), Some(number));
+
+            let after = if i + 1 < KMD_SEASON_ENDS.len() {
+                Some(number + 1)
+            } else {
+                None
+            };
+            assert_eq!(number_of(table.season_at_height(end_height + 1)), after);
+            assert_eq!(number_of(table.season_at_time(end_time + 1)), after);
+        }
+
+        // season 9 started at the end of October 2024
+        assert_eq!(table.season_at_height(4_125_988).unwrap().number, 8);
+        assert_eq!(table.season_at_height(4_125_989).unwrap().number, 9);
+        assert_eq!(table.season_at_time(1_730_271_601).unwrap().number, 9);
+    }
+
+    #[test]
+    fn update_keeps_built_in_notaries() {
+        let client = MockRpc::new(|cmd, _| match cmd {
+            "notaries" => Ok(json!({
+                "notaries": [{
+                    "pubkey": BOB,
+                    "BTCaddress": "1cMh228HTCiwS8ZsaakH8A8wze1JR5ZsP",
+                    "KMDaddress": "R9tYmXuQtH1J1SVmLkZsNeVLiG6by6fLs9"
+                }],
+                "numnotaries": 1,
+                "height": 3_000_000,
+                "timestamp": 0
+            })),
+            _ => unexpected(cmd),
+        });
+        let alice: PublicKey = ALICE.parse().unwrap();
+        let bob: PublicKey = BOB.parse().unwrap();
+
+        let mut table = SeasonTable::kmd();
+        let mut season = table.season(7).unwrap().clone();
+        season.notaries = vec![SeasonNotary {
+            name: Some(String::from("alice_NA")),
+            pubkey: alice,
+        }];
+        table.insert(season);
+
+        let check = table
+            .update(&client, Schedule::Height, 3_000_000, 0)
+            .unwrap();
+        assert_eq!(check.missing, vec![bob]);
+        assert_eq!(check.unexpected, vec![alice]);
+        assert!(table.is_notary_at_height(&alice, 3_000_000));
+        assert!(!table.is_notary_at_height(&bob, 3_000_000));
+
+        // a season without notaries is filled in from the node
+        let mut season = table.season(7).unwrap().clone();
+        season.notaries.clear();
+        table.insert(season);
+        let check = table
+            .update(&client, Schedule::Height, 3_000_000, 0)
+            .unwrap();
+        assert_eq!(check.missing, vec![bob]);
+        assert!(table.is_notary_at_height(&bob, 3_000_000));
+        assert!(table
+            .cross_check(&client, Schedule::Height, 3_000_000, 0)
+            .unwrap()
+            .is_consistent());
+    }
+
+    #[test]
+    fn season_lookup() {
+        let mut table = SeasonTable::kmd();
+        assert_eq!(table.season_at_height(814_000).unwrap().number, 1);
+        assert_eq!(table.season_at_height(814_001).unwrap().number, 2);
+        assert_eq!(table.season_at_time(1_700_000_000).unwrap().number, 8);
+        assert!(table.season_at_height(9_000_000).is_none());
+
+        let pubkey: PublicKey = ALICE.parse().unwrap();
+        let mut season = table.season(7).unwrap().clone();
+        season.notaries = vec![SeasonNotary {
+            name: Some(String::from("alice_NA")),
+            pubkey,
+        }];
+        table.insert(season);
+
+        assert!(table.is_notary_at_height(&pubkey, 3_000_000));
+        assert!(!table.is_notary_at_height(&pubkey, 3_500_000));
+        assert_eq!(table.seasons().len(), 9);
+
+        let notaries: Notaries = serde_json::from_str(
+            r#"{"notaries": [], "numnotaries": 0, "height": 3000000, "timestamp": 0}"#,
+        )
+        .unwrap();
+        let check = table.season(7).unwrap().cross_check(&notaries);
+        assert_eq!(check.unexpected, vec![pubkey]);
+        assert!(!check.is_consistent());
+    }
+}
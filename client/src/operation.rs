@@ -0,0 +1,230 @@
+//! Waiting for the asynchronous operations that the z_ send calls start.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::bitcoin::Txid;
+use crate::json::{OperationId, OperationState, OperationStatus};
+use crate::{Error, Result, RpcApi};
+
+const INITIAL_POLL_INTERVAL: Duration = Duration::from_millis(250);
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// An operation that is running in the daemon, such as a `z_sendmany`.
+pub struct Operation<'a, C: RpcApi> {
+    client: &'a C,
+    id: OperationId,
+    clear_result: bool,
+}
+
+impl<'a, C: RpcApi> Operation<'a, C> {
+    pub fn new(client: &'a C, id: OperationId) -> Self {
+        Operation {
+            client,
+            id,
+            clear_result: false,
+        }
+    }
+
+    /// Removes the result from the daemon once the operation has finished. The daemon keeps
+    /// it otherwise, until it is fetched with `z_getoperationresult`.
+    pub fn clear_result(mut self, clear: bool) -> Self {
+        self.clear_result = clear;
+        self
+    }
+
+    pub fn id(&self) -> &OperationId {
+        &self.id
+    }
+
+    /// The current status, `None` when the daemon doesn't know the operation (anymore).
+    pub fn status(&self) -> Result<Option<OperationStatus>> {
+        let statuses = self
+            .client
+            .z_get_operation_status(Some(&[self.id.clone()]))?;
+
+        Ok(statuses.into_iter().find(|status| status.id == self.id))
+    }
+
+    /// Polls the operation, with an increasing interval, until it succeeded or failed, and
+    /// returns the txid of the transaction it created.
+    pub fn wait(&self, timeout: Duration) -> Result<Txid> {
+        let deadline = Instant::now() + timeout;
+        let mut interval = INITIAL_POLL_INTERVAL;
+
+        loop {
+            let status = self.status()?.ok_or_else(|| {
+                Error::KMDError(format!("operation {} is unknown to the daemon", self.id))
+            })?;
+
+            match status.status {
+                OperationState::Queued | OperationState::Executing => {}
+                _ => {
+                    if self.clear_result {
+                        self.client
+                            .z_get_operation_result(Some(&[self.id.clone()]))?;
+                    }
+
+                    return finished(status);
+                }
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::KMDError(format!(
+                    "operation {} did not finish in time",
+                    self.id
+                )));
+            }
+            thread::sleep(interval.min(deadline - now));
+            interval = (interval * 2).min(MAX_POLL_INTERVAL);
+        }
+    }
+}
+
+fn finished(status: OperationStatus) -> Result<Txid> {
+    match (status.status, status.result, status.error) {
+        (OperationState::Success, Some(result), _) => Ok(result.txid),
+        (_, _, Some(error)) => Err(Error::KMDError(format!(
+            "operation {} failed: {} (code {})",
+            status.id, error.message, error.code
+        ))),
+        (state, _, _) => Err(Error::KMDError(format!(
+            "operation {} finished as {:?} without a result",
+            status.id, state
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{unexpected, MockRpc};
+    use serde_json::{json, Value};
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    const ID: &str = "opid-5b9bc5f9-a4ab-4ed2-a3e0-4c1a2d0bf2bd";
+
+    fn params() -> Value {
+        json!({
+            "fromaddress": "RDragoNHdwovvsDLSLMiAEzEArAD3kq6FN",
+            "amounts": [{"address": "RAqS1bAuWqW2f6ufsU5H4XpKfy5Pqj2oHz", "amount": 1}],
+            "minconf": 1,
+            "fee": 0.0001
+        })
+    }
+
+    fn executing() -> Value {
+        json!({
+            "id": ID,
+            "status": "executing",
+            "creation_time": 1_617_710_470,
+            "method": "z_sendmany",
+            "params": params()
+        })
+    }
+
+    fn success() -> Value {
+        json!({
+            "id": ID,
+            "status": "success",
+            "creation_time": 1_617_710_470,
+            "result": {"txid": "a1f0e2b2ad7b5b93e6a3c4e4e1b5b9f27b7f2fd3c4b5f3e8b1d5d0ef0c3a3b1e"},
+            "execution_secs": 12.716_387_592,
+            "method": "z_sendmany",
+            "params": params()
+        })
+    }
+
+    fn failed() -> Value {
+        json!({
+            "id": ID,
+            "status": "failed",
+            "creation_time": 1_617_710_470,
+            "error": {
+                "code": -6,
+                "message": "Insufficient funds, no UTXOs found for taddr from address."
+            },
+            "method": "z_sendmany",
+            "params": params()
+        })
+    }
+
+    fn id() -> OperationId {
+        OperationId(ID.to_string())
+    }
+
+    #[test]
+    fn parse_statuses() {
+        let statuses: Vec<OperationStatus> =
+            serde_json::from_value(json!([executing(), success(), failed()])).unwrap();
+        assert_eq!(statuses[0].status, OperationState::Executing);
+        assert!(statuses[0].result.is_none());
+
+        assert_eq!(statuses[1].status, OperationState::Success);
+        assert!(finished(statuses[1].clone()).is_ok());
+
+        assert_eq!(statuses[2].error.as_ref().unwrap().code, -6);
+        let error = finished(statuses[2].clone()).unwrap_err().to_string();
+        assert!(error.contains("Insufficient funds"));
+    }
+
+    #[test]
+    fn wait_until_finished() {
+        let polls = Rc::new(Cell::new(0));
+        let client = {
+            let polls = Rc::clone(&polls);
+            MockRpc::new(move |cmd, _| match cmd {
+                "z_getoperationstatus" => {
+                    polls.set(polls.get() + 1);
+                    if polls.get() < 2 {
+                        Ok(json!([executing()]))
+                    } else {
+                        Ok(json!([success()]))
+                    }
+                }
+                "z_getoperationresult" => Ok(json!([success()])),
+                _ => unexpected(cmd),
+            })
+        };
+
+        let txid = Operation::new(&client, id())
+            .clear_result(true)
+            .wait(Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(
+            txid,
+            serde_json::from_value(success()["result"]["txid"].clone()).unwrap()
+        );
+        assert_eq!(polls.get(), 2);
+        assert_eq!(client.calls("z_getoperationresult").len(), 1);
+    }
+
+    #[test]
+    fn wait_fails() {
+        let client = MockRpc::new(|cmd, _| match cmd {
+            "z_getoperationstatus" => Ok(json!([failed()])),
+            _ => unexpected(cmd),
+        });
+        assert!(Operation::new(&client, id())
+            .wait(Duration::from_secs(10))
+            .is_err());
+
+        let client = MockRpc::new(|cmd, _| match cmd {
+            "z_getoperationstatus" => Ok(json!([executing()])),
+            _ => unexpected(cmd),
+        });
+        assert!(Operation::new(&client, id())
+            .wait(Duration::from_millis(0))
+            .is_err());
+
+        let client = MockRpc::new(|cmd, _| match cmd {
+            "z_getoperationstatus" => Ok(json!([])),
+            _ => unexpected(cmd),
+        });
+        let operation = Operation::new(&client, id());
+        assert!(operation.status().unwrap().is_none());
+        assert!(operation.wait(Duration::from_secs(10)).is_err());
+    }
+}
@@ -0,0 +1,291 @@
+//! A portable container for partially signed transactions.
+//!
+//! Raw hex loses the amounts and scripts of the spent outputs, which co-signers need to sign a
+//! Sapling (v4) transaction. A [PartiallySignedTransaction] carries them along with the
+//! redeem scripts and the signatures that were collected so far, and can be passed around as a
+//! file or a base64 string.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::bitcoin::blockdata::script::Instruction;
+use crate::bitcoin::hashes::hex::{FromHex, ToHex};
+use crate::bitcoin::{OutPoint, Script, Txid};
+use crate::json::komodo::util::amount::Amount;
+use crate::json::komodo::PrivateKey;
+use crate::json::{CreateRawTransactionInput, ListUnspentResult, SignRawTransactionInput};
+use crate::multisig::{self, MultisigSpend};
+use crate::{Error, Result, RpcApi};
+
+/// Version of the serialized format.
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PartiallySignedTransaction {
+    pub version: u32,
+    /// The transaction without any signatures.
+    pub unsigned_tx: String,
+    /// The transaction with all the signatures that were collected so far.
+    pub tx: String,
+    pub inputs: Vec<PartiallySignedInput>,
+    pub complete: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PartiallySignedInput {
+    pub txid: Txid,
+    pub vout: u32,
+    #[serde(with = "komodo_rpc_json::komodo::util::amount::serde::as_kmd")]
+    pub amount: Amount,
+    pub script_pubkey: Script,
+    pub redeem_script: Option<Script>,
+    /// Hex encoded signatures, including the sighash type byte.
+    pub signatures: Vec<String>,
+}
+
+impl PartiallySignedInput {
+    pub fn outpoint(&self) -> OutPoint {
+        OutPoint::new(self.txid, self.vout)
+    }
+
+    /// The number of signatures this input needs: M for a multisig, 1 for anything else.
+    pub fn required_signatures(&self) -> usize {
+        self.redeem_script
+            .as_ref()
+            .and_then(multisig::required_signatures)
+            .unwrap_or(1) as usize
+    }
+
+    pub fn missing_signatures(&self) -> usize {
+        self.required_signatures()
+            .saturating_sub(self.signatures.len())
+    }
+
+    fn prevtx(&self) -> SignRawTransactionInput {
+        SignRawTransactionInput {
+            txid: self.txid,
+            vout: self.vout,
+            script_pub_key: self.script_pubkey.clone(),
+            redeem_script: self.redeem_script.clone(),
+            amount: Some(self.amount),
+        }
+    }
+}
+
+impl PartiallySignedTransaction {
+    /// Creates the unsigned transaction that spends `utxos` to `outputs`.
+    pub fn create<C: RpcApi>(
+        client: &C,
+        utxos: &[ListUnspentResult],
+        outputs: &HashMap<String, Amount>,
+        locktime: Option<i64>,
+    ) -> Result<Self> {
+        let inputs: Vec<CreateRawTransactionInput> = utxos
+            .iter()
+            .map(|utxo| CreateRawTransactionInput {
+                txid: utxo.txid,
+                vout: utxo.vout as u32,
+                sequence: None,
+            })
+            .collect();
+        let unsigned_tx = client.createrawtransaction(&inputs, outputs, locktime, None)?;
+
+        let inputs = utxos
+            .iter()
+            .map(|utxo| {
+                Ok(PartiallySignedInput {
+                    txid: utxo.txid,
+                    vout: utxo.vout as u32,
+                    amount: utxo.amount.to_unsigned()?,
+                    script_pubkey: utxo.script_pub_key.clone(),
+                    redeem_script: utxo.redeem_script.clone(),
+                    signatures: vec![],
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(PartiallySignedTransaction::new(unsigned_tx, inputs))
+    }
+
+    pub fn new(unsigned_tx: String, inputs: Vec<PartiallySignedInput>) -> Self {
+        PartiallySignedTransaction {
+            version: FORMAT_VERSION,
+            tx: unsigned_tx.clone(),
+            unsigned_tx,
+            inputs,
+            complete: false,
+        }
+    }
+
+    /// The spent outputs, as `signrawtransaction` takes them.
+    pub fn prevtxs(&self) -> Vec<SignRawTransactionInput> {
+        self.inputs
+            .iter()
+            .map(PartiallySignedInput::prevtx)
+            .collect()
+    }
+
+    /// The number of signatures that are still needed, summed over all inputs.
+    pub fn missing_signatures(&self) -> usize {
+        self.inputs
+            .iter()
+            .map(PartiallySignedInput::missing_signatures)
+            .sum()
+    }
+
+    /// Adds signatures with `privkeys`, or with the keys in the wallet when `privkeys` is
+    /// `None`. The signatures that were already collected are kept.
+    pub fn sign<C: RpcApi>(&mut self, client: &C, privkeys: Option<&[PrivateKey]>) -> Result<()> {
+        let signed = client.signrawtransaction(&self.tx, Some(&self.prevtxs()), privkeys, None)?;
+
+        self.update(client, signed.hex, signed.complete)
+    }
+
+    /// Merges the signatures of `other`, which must spend the same unsigned transaction.
+    ///
+    /// Both transactions are passed to `signrawtransaction` without keys, which makes the
+    /// daemon only combine the signatures that are already there.
+    pub fn combine<C: RpcApi>(
+        &mut self,
+        client: &C,
+        other: &PartiallySignedTransaction,
+    ) -> Result<()> {
+        if self.unsigned_tx != other.unsigned_tx {
+            return Err(Error::KMDError(String::from(
+                "partially signed transactions spend different transactions",
+            )));
+        }
+
+        let concatenated = format!("{}{}", self.tx, other.tx);
+        let merged =
+            client.signrawtransaction(&concatenated, Some(&self.prevtxs()), Some(&[]), None)?;
+
+        self.update(client, merged.hex, merged.complete)
+    }
+
+    // Reads the collected signatures back from the scriptSigs of the daemon's transaction.
+    fn update<C: RpcApi>(&mut self, client: &C, hex: String, complete: bool) -> Result<()> {
+        let decoded = client.decoderawtransaction(&hex)?;
+        if decoded.vin.len() != self.inputs.len() {
+            return Err(Error::KMDError(String::from(
+                "signed transaction has a different number of inputs",
+            )));
+        }
+
+        for (input, vin) in self.inputs.iter_mut().zip(decoded.vin.iter()) {
+            let bytes: Vec<u8> = FromHex::from_hex(&vin.script_sig.hex)
+                .map_err(|_| Error::KMDError(String::from("invalid scriptSig hex")))?;
+            let script_sig = Script::from(bytes);
+
+            let signatures = match input.redeem_script {
+                Some(ref redeem_script) => multisig::signatures(&script_sig, redeem_script),
+                // P2PKH and P2PK scriptSigs start with the signature
+                None => match script_sig.instructions().next() {
+                    Some(Ok(Instruction::PushBytes(sig))) if !sig.is_empty() => vec![sig.to_vec()],
+                    _ => vec![],
+                },
+            };
+            input.signatures = signatures.iter().map(|sig| sig.to_hex()).collect();
+        }
+
+        self.tx = hex;
+        self.complete = complete;
+
+        Ok(())
+    }
+
+    /// Broadcasts the transaction once it has all signatures.
+    pub fn broadcast<C: RpcApi>(&self, client: &C) -> Result<Txid> {
+        if !self.complete {
+            return Err(Error::KMDError(format!(
+                "{} more signature(s) needed",
+                self.missing_signatures().max(1)
+            )));
+        }
+
+        client.sendrawtransaction(&self.tx, None)
+    }
+
+    pub fn to_base64(&self) -> Result<String> {
+        Ok(base64::encode(serde_json::to_vec(self)?))
+    }
+
+    pub fn from_base64(s: &str) -> Result<Self> {
+        let bytes = base64::decode(s.trim())?;
+
+        PartiallySignedTransaction::from_json(&bytes)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        PartiallySignedTransaction::from_json(&fs::read(path)?)
+    }
+
+    fn from_json(bytes: &[u8]) -> Result<Self> {
+        let pst: PartiallySignedTransaction = serde_json::from_slice(bytes)?;
+        if pst.version != FORMAT_VERSION {
+            return Err(Error::KMDError(format!(
+                "unsupported partially signed transaction version {}",
+                pst.version
+            )));
+        }
+
+        Ok(pst)
+    }
+}
+
+impl From<&MultisigSpend> for PartiallySignedTransaction {
+    fn from(spend: &MultisigSpend) -> Self {
+        let inputs = spend
+            .prevouts
+            .iter()
+            .map(|prevout| PartiallySignedInput {
+                txid: prevout.txid,
+                vout: prevout.vout,
+                amount: prevout.amount.unwrap_or(Amount::from_sat(0)),
+                script_pubkey: prevout.script_pub_key.clone(),
+                redeem_script: prevout.redeem_script.clone(),
+                signatures: vec![],
+            })
+            .collect();
+
+        PartiallySignedTransaction::new(spend.unsigned_hex.clone(), inputs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_roundtrip() {
+        let redeem_script = Script::from(
+            Vec::<u8>::from_hex(
+                "51210279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f8179851ae",
+            )
+            .unwrap(),
+        );
+        let pst = PartiallySignedTransaction::new(
+            String::from("0400008085202f89"),
+            vec![PartiallySignedInput {
+                txid: Txid::default(),
+                vout: 1,
+                amount: Amount::from_sat(123_456_789),
+                script_pubkey: redeem_script.to_p2sh(),
+                redeem_script: Some(redeem_script),
+                signatures: vec![],
+            }],
+        );
+
+        let decoded = PartiallySignedTransaction::from_base64(&pst.to_base64().unwrap()).unwrap();
+        assert_eq!(decoded, pst);
+        assert_eq!(decoded.missing_signatures(), 1);
+        assert!(!decoded.complete);
+    }
+}
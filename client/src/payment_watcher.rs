@@ -0,0 +1,641 @@
+//! Watching addresses for incoming payments.
+//!
+//! Transparent addresses are followed with `listsinceblock`, shielded addresses with
+//! `z_listreceivedbyaddress`; both need the addresses to be in the wallet, or imported as watch
+//! only. Payments are reported when they arrive, when they reach the required number of
+//! confirmations and when they leave the chain and the mempool before that. The wallet keeps
+//! listing dropped, expired and double spent transactions, so unconfirmed payments are checked
+//! against the mempool.
+//!
+//! The cursor into the chain, the payments that are still waiting for confirmations and the
+//! confirmed payments the wallet can still list are part of the watcher's state.
+//! [`PaymentWatcher::save`] it after the events of a poll were handled and
+//! [`PaymentWatcher::load`] it on restart: events that weren't saved are reported again, events
+//! that were aren't.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::bitcoin::{BlockHash, Txid};
+use crate::json::komodo::util::address::AddressType;
+use crate::json::komodo::util::amount::Amount;
+use crate::json::{Address, ListSinceBlockCategory};
+use crate::{Result, RpcApi};
+
+/// Blocks after which a confirmed transparent payment is forgotten. `listsinceblock` only lists
+/// it again after a reorg that deep, which notarizations rule out.
+const REORG_HORIZON: u64 = 100;
+
+/// Identifies a payment within its transaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PaymentId {
+    pub txid: Txid,
+    /// The transparent or Sapling output, or the output of the Sprout joinsplit.
+    pub output: u32,
+    /// The Sprout joinsplit of the output.
+    pub joinsplit: Option<u32>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Payment {
+    pub id: PaymentId,
+    pub address: Address,
+    #[serde(with = "crate::json::komodo::util::amount::serde::as_kmd")]
+    pub amount: Amount,
+    /// Not adjusted for notarizations.
+    pub confirmations: u32,
+    /// Only known for transparent payments in a block.
+    pub blockhash: Option<BlockHash>,
+}
+
+impl Payment {
+    pub fn is_shielded(&self) -> bool {
+        is_shielded(&self.address)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum PaymentEvent {
+    /// The payment was seen for the first time, in the mempool or in a block.
+    Arrived(Payment),
+    /// The payment reached the required confirmations. It isn't followed anymore.
+    Confirmed(Payment),
+    /// The payment is neither in the chain nor in the mempool anymore.
+    Reorged(Payment),
+}
+
+/// A watched address and what it received so far.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Watch {
+    pub address: Address,
+    #[serde(with = "crate::json::komodo::util::amount::serde::as_kmd::opt")]
+    pub expected: Option<Amount>,
+    /// The sum of the confirmed payments.
+    #[serde(with = "crate::json::komodo::util::amount::serde::as_kmd")]
+    pub received: Amount,
+}
+
+impl Watch {
+    /// Whether the confirmed payments add up to the expected amount. Always `false` without
+    /// an expected amount.
+    pub fn is_paid(&self) -> bool {
+        self.expected
+            .map_or(false, |expected| self.received >= expected)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Confirmed {
+    id: PaymentId,
+    address: Address,
+    /// The block count when the payment was confirmed.
+    height: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct State {
+    cursor: Option<BlockHash>,
+    watches: Vec<Watch>,
+    pending: Vec<Payment>,
+    // shielded payments are listed on every poll, so they are remembered as long as their
+    // address is watched, transparent ones only until a reorg can't list them again
+    confirmed: Vec<Confirmed>,
+}
+
+pub struct PaymentWatcher<'a, C: RpcApi> {
+    client: &'a C,
+    confirmations: u32,
+    state: State,
+    // the block count at the start of the poll
+    height: u64,
+}
+
+impl<'a, C: RpcApi> PaymentWatcher<'a, C> {
+    /// A watcher that considers payments with `confirmations` confirmations final. The first
+    /// poll reports everything the watched addresses received before.
+    pub fn new(client: &'a C, confirmations: u32) -> Self {
+        PaymentWatcher {
+            client,
+            confirmations: confirmations.max(1),
+            state: State::default(),
+            height: 0,
+        }
+    }
+
+    /// Restores the state saved at `path`, or starts like [`new`](PaymentWatcher::new) if there
+    /// is no file yet.
+    pub fn load<P: AsRef<Path>>(client: &'a C, confirmations: u32, path: P) -> Result<Self> {
+        let mut watcher = PaymentWatcher::new(client, confirmations);
+        match fs::read_to_string(path) {
+            Ok(json) => watcher.state = serde_json::from_str(&json)?,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(watcher)
+    }
+
+    /// Saves the state to `path`, replacing the previous state in one step.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(&self.state)?)?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// Starts watching `address`, or changes the amount it is expected to receive.
+    ///
+    /// Transparent payments that are older than the cursor aren't reported, so addresses
+    /// should be watched before they are handed out.
+    pub fn watch(&mut self, address: Address, expected: Option<Amount>) {
+        match self.watch_mut(&address) {
+            Some(watch) => watch.expected = expected,
+            None => self.state.watches.push(Watch {
+                address,
+                expected,
+                received: Amount::from_sat(0),
+            }),
+        }
+    }
+
+    /// Stops watching `address` and forgets its pending and confirmed payments.
+    pub fn unwatch(&mut self, address: &Address) -> Option<Watch> {
+        let address = address.to_string();
+        self.state
+            .pending
+            .retain(|p| p.address.to_string() != address);
+        self.state
+            .confirmed
+            .retain(|c| c.address.to_string() != address);
+        let position = self
+            .state
+            .watches
+            .iter()
+            .position(|w| w.address.to_string() == address)?;
+        Some(self.state.watches.remove(position))
+    }
+
+    pub fn watches(&self) -> &[Watch] {
+        &self.state.watches
+    }
+
+    pub fn get_watch(&self, address: &Address) -> Option<&Watch> {
+        let address = address.to_string();
+        self.state
+            .watches
+            .iter()
+            .find(|w| w.address.to_string() == address)
+    }
+
+    /// Payments that arrived but don't have the required confirmations yet.
+    pub fn pending(&self) -> &[Payment] {
+        &self.state.pending
+    }
+
+    /// The last block the transparent addresses were checked up to.
+    pub fn cursor(&self) -> Option<BlockHash> {
+        self.state.cursor
+    }
+
+    pub fn poll(&mut self) -> Result<Vec<PaymentEvent>> {
+        // taken before the payments are listed, so a transaction that is mined in between isn't
+        // taken for dropped
+        let mempool: HashSet<Txid> = self.client.get_raw_mempool()?.into_iter().collect();
+        self.height = self.client.get_block_count()? as u64;
+
+        let mut events = self.poll_transparent(&mempool)?;
+        events.extend(self.poll_shielded(&mempool)?);
+
+        let horizon = self.height.saturating_sub(REORG_HORIZON);
+        self.state
+            .confirmed
+            .retain(|c| is_shielded(&c.address) || c.height > horizon);
+        Ok(events)
+    }
+
+    fn poll_transparent(&mut self, mempool: &HashSet<Txid>) -> Result<Vec<PaymentEvent>> {
+        // `listsinceblock` returns the block `confirmations` deep as the next cursor, so
+        // payments keep showing up until they are confirmed
+        let since = self.client.list_since_block(
+            self.state.cursor.as_ref(),
+            Some(self.confirmations as usize),
+            Some(true),
+        )?;
+
+        let mut events = vec![];
+        let mut seen = HashSet::new();
+        for tx in since.transactions {
+            let address = match (tx.category, tx.address) {
+                (ListSinceBlockCategory::Receive, Some(address)) => address,
+                _ => continue,
+            };
+            if is_shielded(&address) || self.get_watch(&address).is_none() {
+                continue;
+            }
+
+            let payment = Payment {
+                id: PaymentId {
+                    txid: tx.txid,
+                    output: tx.vout as u32,
+                    joinsplit: None,
+                },
+                address,
+                amount: tx.amount.to_unsigned()?,
+                // conflicted transactions count as unconfirmed
+                confirmations: tx.rawconfirmations.unwrap_or(tx.confirmations).max(0) as u32,
+                blockhash: tx.blockhash,
+            };
+            seen.insert(payment.id);
+            self.observe(payment, mempool, &mut events);
+        }
+
+        self.remove_unseen(false, &seen, &mut events);
+        self.state.cursor = Some(since.lastblock);
+        Ok(events)
+    }
+
+    fn poll_shielded(&mut self, mempool: &HashSet<Txid>) -> Result<Vec<PaymentEvent>> {
+        let addresses: Vec<Address> = self
+            .state
+            .watches
+            .iter()
+            .map(|w| w.address.clone())
+            .filter(is_shielded)
+            .collect();
+
+        let mut events = vec![];
+        let mut seen = HashSet::new();
+        for address in addresses {
+            for note in self.client.z_list_received_by_address(&address, Some(0))? {
+                if note.change {
+                    continue;
+                }
+                let id = PaymentId {
+                    txid: note.txid,
+                    output: note.outindex.or(note.jsoutindex).unwrap_or(0),
+                    joinsplit: note.jsindex,
+                };
+                seen.insert(id);
+
+                let payment = Payment {
+                    id,
+                    address: address.clone(),
+                    amount: note.amount,
+                    confirmations: note.rawconfirmations.or(note.confirmations).unwrap_or(0),
+                    blockhash: None,
+                };
+                self.observe(payment, mempool, &mut events);
+            }
+        }
+
+        self.remove_unseen(true, &seen, &mut events);
+        Ok(events)
+    }
+
+    fn observe(
+        &mut self,
+        payment: Payment,
+        mempool: &HashSet<Txid>,
+        events: &mut Vec<PaymentEvent>,
+    ) {
+        if self.state.confirmed.iter().any(|c| c.id == payment.id) {
+            return;
+        }
+        // listed, but neither in a block nor in the mempool
+        if payment.confirmations == 0 && !mempool.contains(&payment.id.txid) {
+            if let Some(i) = self.state.pending.iter().position(|p| p.id == payment.id) {
+                events.push(PaymentEvent::Reorged(self.state.pending.remove(i)));
+            }
+            return;
+        }
+        self.update(payment, events);
+    }
+
+    fn update(&mut self, payment: Payment, events: &mut Vec<PaymentEvent>) {
+        let pending = self.state.pending.iter().position(|p| p.id == payment.id);
+
+        if payment.confirmations < self.confirmations {
+            match pending {
+                Some(i) => self.state.pending[i] = payment,
+                None => {
+                    self.state.pending.push(payment.clone());
+                    events.push(PaymentEvent::Arrived(payment));
+                }
+            }
+            return;
+        }
+
+        match pending {
+            Some(i) => {
+                self.state.pending.remove(i);
+            }
+            // confirmed before the watcher saw it
+            None => events.push(PaymentEvent::Arrived(payment.clone())),
+        }
+        self.state.confirmed.push(Confirmed {
+            id: payment.id,
+            address: payment.address.clone(),
+            height: self.height,
+        });
+        if let Some(watch) = self.watch_mut(&payment.address) {
+            watch.received += payment.amount;
+        }
+        events.push(PaymentEvent::Confirmed(payment));
+    }
+
+    // Pending payments are listed until they are confirmed or removed from the wallet, so the
+    // ones that weren't are gone.
+    fn remove_unseen(
+        &mut self,
+        shielded: bool,
+        seen: &HashSet<PaymentId>,
+        events: &mut Vec<PaymentEvent>,
+    ) {
+        let (removed, kept): (Vec<Payment>, Vec<Payment>) = self
+            .state
+            .pending
+            .drain(..)
+            .partition(|p| p.is_shielded() == shielded && !seen.contains(&p.id));
+        self.state.pending = kept;
+        events.extend(removed.into_iter().map(PaymentEvent::Reorged));
+    }
+
+    fn watch_mut(&mut self, address: &Address) -> Option<&mut Watch> {
+        let address = address.to_string();
+        self.state
+            .watches
+            .iter_mut()
+            .find(|w| w.address.to_string() == address)
+    }
+}
+
+fn is_shielded(address: &Address) -> bool {
+    match address.addr_type {
+        AddressType::Shielded => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::env;
+    use std::rc::Rc;
+
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::mock::{txid, unexpected, MockRpc};
+
+    const ALICE: &str = "RAqS1bAuWqW2f6ufsU5H4XpKfy5Pqj2oHz";
+    const CAROL: &str =
+        "zs10rwlj7kp9usz9ejhlwt9p57dgfs9srfj9zm9yunkx6vxnavy6g6z6ugkg2mjdvzyq938ct8lwvp";
+
+    #[derive(Default)]
+    struct Wallet {
+        // txid and confirmations of the payments to ALICE
+        listed: Vec<(Txid, i32)>,
+        // txid and confirmations of the notes received by CAROL
+        notes: Vec<(Txid, u32)>,
+        mempool: Vec<Txid>,
+        height: u64,
+    }
+
+    fn client(wallet: &Rc<RefCell<Wallet>>) -> MockRpc {
+        let wallet = Rc::clone(wallet);
+        MockRpc::new(move |cmd, args| {
+            let wallet = wallet.borrow();
+            match cmd {
+                "getrawmempool" => Ok(json!(wallet.mempool)),
+                "getblockcount" => Ok(json!(wallet.height)),
+                "z_listreceivedbyaddress" => {
+                    assert_eq!(args[0], CAROL);
+                    let notes: Vec<Value> = wallet
+                        .notes
+                        .iter()
+                        .map(|(txid, confirmations)| {
+                            json!({
+                                "txid": txid,
+                                "amount": 1.0,
+                                "memo": "f6",
+                                "outindex": 0,
+                                "confirmations": confirmations,
+                                "rawconfirmations": confirmations,
+                                "change": false
+                            })
+                        })
+                        .collect();
+                    Ok(json!(notes))
+                }
+                "listsinceblock" => {
+                    let transactions: Vec<Value> = wallet
+                        .listed
+                        .iter()
+                        .map(|(txid, confirmations)| {
+                            json!({
+                                "account": "",
+                                "address": ALICE,
+                                "category": "receive",
+                                "amount": 1.0,
+                                "vout": 0,
+                                "confirmations": confirmations,
+                                "rawconfirmations": confirmations,
+                                "blockhash": if *confirmations > 0 {
+                                    json!(BlockHash::default())
+                                } else {
+                                    Value::Null
+                                },
+                                "txid": txid,
+                                "time": 1_600_000_000,
+                                "timereceived": 1_600_000_000
+                            })
+                        })
+                        .collect();
+                    Ok(json!({
+                        "transactions": transactions,
+                        "lastblock": BlockHash::default()
+                    }))
+                }
+                _ => unexpected(cmd),
+            }
+        })
+    }
+
+    fn alice() -> Address {
+        serde_json::from_value(json!(ALICE)).unwrap()
+    }
+
+    fn carol() -> Address {
+        serde_json::from_value(json!(CAROL)).unwrap()
+    }
+
+    // The kind of each event and the txid of its payment.
+    fn events<C: RpcApi>(watcher: &mut PaymentWatcher<C>) -> Vec<(&'static str, Txid)> {
+        watcher
+            .poll()
+            .unwrap()
+            .into_iter()
+            .map(|event| match event {
+                PaymentEvent::Arrived(p) => ("arrived", p.id.txid),
+                PaymentEvent::Confirmed(p) => ("confirmed", p.id.txid),
+                PaymentEvent::Reorged(p) => ("reorged", p.id.txid),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn confirms_once_across_restarts() {
+        let path = env::temp_dir().join(format!("payment_watcher_{}.json", std::process::id()));
+        let wallet = Rc::new(RefCell::new(Wallet::default()));
+        let client = client(&wallet);
+        let mut watcher = PaymentWatcher::new(&client, 2);
+        watcher.watch(alice(), Some(Amount::from_sat(100_000_000)));
+
+        *wallet.borrow_mut() = Wallet {
+            listed: vec![(txid(1), 0)],
+            mempool: vec![txid(1)],
+            height: 100,
+            ..Wallet::default()
+        };
+        assert_eq!(events(&mut watcher), vec![("arrived", txid(1))]);
+        watcher.save(&path).unwrap();
+
+        // not reported again after a restart
+        let mut watcher = PaymentWatcher::load(&client, 2, &path).unwrap();
+        assert!(events(&mut watcher).is_empty());
+        assert_eq!(watcher.pending().len(), 1);
+
+        *wallet.borrow_mut() = Wallet {
+            listed: vec![(txid(1), 2)],
+            mempool: vec![],
+            height: 102,
+            ..Wallet::default()
+        };
+        assert_eq!(events(&mut watcher), vec![("confirmed", txid(1))]);
+        assert!(watcher.get_watch(&alice()).unwrap().is_paid());
+        watcher.save(&path).unwrap();
+
+        // a reorg below the cursor lists the payment again
+        let mut watcher = PaymentWatcher::load(&client, 2, &path).unwrap();
+        wallet.borrow_mut().listed = vec![(txid(1), 1)];
+        assert!(events(&mut watcher).is_empty());
+        assert_eq!(
+            watcher.get_watch(&alice()).unwrap().received,
+            Amount::from_sat(100_000_000)
+        );
+        assert!(watcher.pending().is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reports_payments_that_leave_the_chain() {
+        let wallet = Rc::new(RefCell::new(Wallet::default()));
+        let client = client(&wallet);
+        let mut watcher = PaymentWatcher::new(&client, 10);
+        watcher.watch(alice(), None);
+
+        *wallet.borrow_mut() = Wallet {
+            listed: vec![(txid(1), 0), (txid(2), 1), (txid(3), 1)],
+            mempool: vec![txid(1)],
+            height: 100,
+            ..Wallet::default()
+        };
+        assert_eq!(events(&mut watcher).len(), 3);
+
+        // the first one was dropped from the mempool, the second one went back to it and the
+        // third one conflicts with a transaction in the chain, all still listed by the wallet
+        *wallet.borrow_mut() = Wallet {
+            listed: vec![(txid(1), 0), (txid(2), 0), (txid(3), -1)],
+            mempool: vec![txid(2)],
+            height: 100,
+            ..Wallet::default()
+        };
+        let mut reorged = events(&mut watcher);
+        let mut expected = vec![("reorged", txid(1)), ("reorged", txid(3))];
+        reorged.sort();
+        expected.sort();
+        assert_eq!(reorged, expected);
+        assert_eq!(watcher.pending().len(), 1);
+        assert!(events(&mut watcher).is_empty());
+
+        // rebroadcast
+        wallet.borrow_mut().mempool.push(txid(1));
+        assert_eq!(events(&mut watcher), vec![("arrived", txid(1))]);
+
+        // no longer listed at all
+        wallet.borrow_mut().listed.clear();
+        assert_eq!(events(&mut watcher).len(), 2);
+        assert!(watcher.pending().is_empty());
+    }
+
+    #[test]
+    fn follows_sapling_payments() {
+        let wallet = Rc::new(RefCell::new(Wallet::default()));
+        let client = client(&wallet);
+        let mut watcher = PaymentWatcher::new(&client, 2);
+        watcher.watch(carol(), None);
+
+        *wallet.borrow_mut() = Wallet {
+            notes: vec![(txid(1), 0), (txid(2), 0)],
+            mempool: vec![txid(1), txid(2)],
+            height: 100,
+            ..Wallet::default()
+        };
+        assert_eq!(
+            events(&mut watcher),
+            vec![("arrived", txid(1)), ("arrived", txid(2))]
+        );
+        assert!(watcher.pending().iter().all(Payment::is_shielded));
+
+        // the first one is mined, the second one dropped from the mempool but still listed
+        *wallet.borrow_mut() = Wallet {
+            notes: vec![(txid(1), 2), (txid(2), 0)],
+            height: 102,
+            ..Wallet::default()
+        };
+        assert_eq!(
+            events(&mut watcher),
+            vec![("confirmed", txid(1)), ("reorged", txid(2))]
+        );
+        assert_eq!(
+            watcher.get_watch(&carol()).unwrap().received,
+            Amount::from_sat(100_000_000)
+        );
+
+        // the note is listed on every poll, long after a transparent payment would be forgotten
+        wallet.borrow_mut().height += REORG_HORIZON * 2;
+        assert!(events(&mut watcher).is_empty());
+        assert!(watcher.pending().is_empty());
+
+        watcher.unwatch(&carol());
+        assert!(watcher.state.confirmed.is_empty());
+    }
+
+    #[test]
+    fn forgets_deep_transparent_payments() {
+        let wallet = Rc::new(RefCell::new(Wallet::default()));
+        let client = client(&wallet);
+        let mut watcher = PaymentWatcher::new(&client, 1);
+        watcher.watch(alice(), None);
+
+        *wallet.borrow_mut() = Wallet {
+            listed: vec![(txid(1), 1)],
+            height: 100,
+            ..Wallet::default()
+        };
+        assert_eq!(events(&mut watcher).len(), 2);
+
+        // past the cursor, `listsinceblock` doesn't list it anymore
+        wallet.borrow_mut().listed.clear();
+        wallet.borrow_mut().height = 100 + REORG_HORIZON - 1;
+        assert!(events(&mut watcher).is_empty());
+        assert_eq!(watcher.state.confirmed.len(), 1);
+
+        wallet.borrow_mut().height += 1;
+        assert!(events(&mut watcher).is_empty());
+        assert!(watcher.state.confirmed.is_empty());
+    }
+}
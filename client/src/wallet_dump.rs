@@ -0,0 +1,362 @@
+//! Parsing the text files that `dumpwallet` and `z_exportwallet` write, and verifying that such
+//! a backup holds the keys of every address in the wallet.
+//!
+//! Dumps record when a key was created as a time, not a height. [`birth_height`] looks up the
+//! height to start a rescan at for such a time.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use crate::bitcoin::BlockHash;
+use crate::json::komodo::PrivateKey;
+use crate::json::Address;
+use crate::{Error, Result, RpcApi};
+
+/// How far block times can lag behind the time a key was created, as in the wallet's rescans.
+const TIMESTAMP_WINDOW: u64 = 2 * 60 * 60;
+
+#[derive(Clone, Debug)]
+pub struct WalletDump {
+    /// e.g. `Komodo v0.7.1`
+    pub created_by: Option<String>,
+    pub best_block_height: Option<u64>,
+    pub best_block_hash: Option<BlockHash>,
+    pub keys: Vec<DumpedKey>,
+    /// Only in dumps made by `z_exportwallet`.
+    pub shielded_keys: Vec<DumpedShieldedKey>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeyKind {
+    /// The key belongs to an address in the address book, with this label.
+    Label(String),
+    Change,
+    /// The key is in the keypool and has not been handed out yet.
+    Reserve,
+    Unknown,
+}
+
+#[derive(Clone, Debug)]
+pub struct DumpedKey {
+    pub key: PrivateKey,
+    /// Creation time of the key as UNIX timestamp, 0 when the wallet doesn't know it.
+    pub birth_time: u64,
+    pub kind: KeyKind,
+    pub address: Address,
+}
+
+impl DumpedKey {
+    /// See [`birth_height`].
+    pub fn birth_height<C: RpcApi>(&self, client: &C) -> Result<u64> {
+        birth_height(client, self.birth_time)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DumpedShieldedKey {
+    /// The encoded spending key, `secret-extended-key-main1...` for Sapling.
+    pub spending_key: String,
+    pub birth_time: u64,
+    pub address: Address,
+}
+
+impl DumpedShieldedKey {
+    /// See [`birth_height`].
+    pub fn birth_height<C: RpcApi>(&self, client: &C) -> Result<u64> {
+        birth_height(client, self.birth_time)
+    }
+}
+
+/// The first block that can hold transactions of a key created at `birth_time`: the lowest
+/// height whose block time is at most two hours before it. Block times aren't strictly
+/// increasing, so this is found by a binary search over the chain that allows for that window.
+/// Returns the tip if no block is recent enough.
+pub fn birth_height<C: RpcApi>(client: &C, birth_time: u64) -> Result<u64> {
+    let earliest = birth_time.saturating_sub(TIMESTAMP_WINDOW);
+    let (mut low, mut high) = (0, client.get_block_count()? as u64);
+
+    while low < high {
+        let middle = low + (high - low) / 2;
+        let hash = client.get_block_hash(middle)?;
+        if client.get_block(&hash)?.time < earliest {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+
+    Ok(low)
+}
+
+impl WalletDump {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        WalletDump::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut dump = WalletDump {
+            created_by: None,
+            best_block_height: None,
+            best_block_hash: None,
+            keys: vec![],
+            shielded_keys: vec![],
+        };
+
+        for (n, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            let invalid = |what: &str| Error::KMDError(format!("line {}: invalid {}", n + 1, what));
+
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(comment) = line.strip_prefix('#') {
+                dump.parse_header(comment.trim());
+                continue;
+            }
+
+            // <key> <time> [<kind>] # addr=<address> or # zaddr=<address>
+            let mut halves = line.splitn(2, '#');
+            let fields: Vec<&str> = halves.next().unwrap_or("").split_whitespace().collect();
+            let comment = halves.next().unwrap_or("").trim();
+
+            if fields.len() < 2 {
+                return Err(invalid("key line"));
+            }
+            let birth_time = parse_time(fields[1]).ok_or_else(|| invalid("time"))?;
+
+            if let Some(zaddr) = comment.strip_prefix("zaddr=") {
+                dump.shielded_keys.push(DumpedShieldedKey {
+                    spending_key: String::from(fields[0]),
+                    birth_time,
+                    address: zaddr.parse().map_err(|_| invalid("shielded address"))?,
+                });
+            } else if let Some(addr) = comment.strip_prefix("addr=") {
+                let kind = fields
+                    .get(2)
+                    .map_or(KeyKind::Unknown, |kind| parse_kind(kind));
+                dump.keys.push(DumpedKey {
+                    key: fields[0].parse().map_err(|_| invalid("private key"))?,
+                    birth_time,
+                    kind,
+                    address: addr.parse().map_err(|_| invalid("address"))?,
+                });
+            } else {
+                return Err(invalid("address comment"));
+            }
+        }
+
+        Ok(dump)
+    }
+
+    fn parse_header(&mut self, comment: &str) {
+        if let Some(created_by) = comment.strip_prefix("Wallet dump created by ") {
+            self.created_by = Some(String::from(created_by));
+        } else if let Some(best_block) = comment.strip_prefix("* Best block at time of backup was ")
+        {
+            // 123456 (00000000...),
+            let mut parts = best_block.trim_end_matches(',').split_whitespace();
+            self.best_block_height = parts.next().and_then(|height| height.parse().ok());
+            self.best_block_hash = parts
+                .next()
+                .map(|hash| hash.trim_matches(|c| c == '(' || c == ')'))
+                .and_then(|hash| hash.parse().ok());
+        }
+    }
+
+    pub fn addresses(&self) -> impl Iterator<Item = &Address> {
+        self.keys
+            .iter()
+            .map(|key| &key.address)
+            .chain(self.shielded_keys.iter().map(|key| &key.address))
+    }
+
+    /// Checks that the dump holds a key for every address that the wallet listing calls
+    /// return: `listreceivedbyaddress`, `listunspent` and `z_listaddresses`. Watch-only
+    /// addresses are left out, the wallet has no keys for them.
+    pub fn verify<C: RpcApi>(&self, client: &C) -> Result<BackupReport> {
+        let dumped: HashSet<String> = self.addresses().map(|a| a.to_string()).collect();
+        let mut wallet: HashSet<String> = HashSet::new();
+
+        for received in client.list_received_by_address(Some(0), Some(true), Some(false))? {
+            if received.involves_watch_only != Some(true) {
+                wallet.insert(received.address.to_string());
+            }
+        }
+        for unspent in client.list_unspent(Some(0), None, None)? {
+            if let (true, Some(address)) = (unspent.spendable, unspent.address) {
+                wallet.insert(address.to_string());
+            }
+        }
+        let shielded: HashSet<String> = client
+            .z_list_addresses(Some(false))?
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+
+        let mut missing: Vec<String> = wallet.difference(&dumped).cloned().collect();
+        let mut missing_shielded: Vec<String> = shielded.difference(&dumped).cloned().collect();
+        missing.sort();
+        missing_shielded.sort();
+
+        Ok(BackupReport {
+            checked: wallet.len() + shielded.len(),
+            missing,
+            missing_shielded,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BackupReport {
+    /// The number of wallet addresses that were checked.
+    pub checked: usize,
+    /// Transparent addresses without a key in the backup.
+    pub missing: Vec<String>,
+    /// Shielded addresses without a key in the backup. A `dumpwallet` backup has no shielded
+    /// keys, use `z_exportwallet` to include them.
+    pub missing_shielded: Vec<String>,
+}
+
+impl BackupReport {
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty() && self.missing_shielded.is_empty()
+    }
+}
+
+fn parse_kind(kind: &str) -> KeyKind {
+    match kind {
+        "change=1" => KeyKind::Change,
+        "reserve=1" => KeyKind::Reserve,
+        _ => match kind.strip_prefix("label=") {
+            Some(label) => KeyKind::Label(decode_label(label)),
+            None => KeyKind::Unknown,
+        },
+    }
+}
+
+/// Labels are written with spaces, `%` and non-printable characters as `%XX`.
+fn decode_label(label: &str) -> String {
+    let bytes = label.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(byte) = hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Parses `2020-01-31T12:00:00Z` into a UNIX timestamp.
+fn parse_time(time: &str) -> Option<u64> {
+    let time = time.strip_suffix('Z')?;
+    let mut date_time = time.splitn(2, 'T');
+    let date: Vec<i64> = date_time
+        .next()?
+        .split('-')
+        .map(|part| part.parse().ok())
+        .collect::<Option<_>>()?;
+    let clock: Vec<i64> = date_time
+        .next()?
+        .split(':')
+        .map(|part| part.parse().ok())
+        .collect::<Option<_>>()?;
+    if date.len() != 3 || clock.len() != 3 {
+        return None;
+    }
+
+    // days since 1970-01-01 in the proleptic Gregorian calendar
+    let (year, month, day) = (date[0], date[1], date[2]);
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let seconds = days * 86_400 + clock[0] * 3600 + clock[1] * 60 + clock[2];
+    if seconds < 0 {
+        return None;
+    }
+
+    Some(seconds as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{unexpected, MockChain, MockRpc};
+
+    const DUMP: &str = "\
+# Wallet dump created by Komodo v0.7.1
+# * Created on 2021-06-01T10:00:00Z
+# * Best block at time of backup was 2400000 (0000000000000000000000000000000000000000000000000000000000000001),
+#   mined on 2021-06-01T09:59:00Z
+
+Up1YVLk7uuErCHVQyFCtfinZngmdwfyfc47WCQ8oJxgowjVzNeqs 2021-05-31T00:00:00Z label=cold%20storage # addr=RKxTdfmtxtfLDKZBgx6SvNkBtNu9jRYnLh
+Up1YVLk7uuErCHVQyFCtfinZngmdwfyfc47WCQ8oJxgoxEMG4fjy 1970-01-01T00:00:01Z reserve=1 # addr=R9tYmXuQtH1J1SVmLkZsNeVLiG6by6fLs9
+
+# End of dump
+";
+
+    #[test]
+    fn parse_dump() {
+        let dump = WalletDump::parse(DUMP).unwrap();
+
+        assert_eq!(dump.created_by.as_deref(), Some("Komodo v0.7.1"));
+        assert_eq!(dump.best_block_height, Some(2_400_000));
+        assert!(dump.best_block_hash.is_some());
+        assert_eq!(dump.keys.len(), 2);
+        assert!(dump.shielded_keys.is_empty());
+
+        let first = &dump.keys[0];
+        assert_eq!(first.kind, KeyKind::Label(String::from("cold storage")));
+        assert_eq!(first.birth_time, 1_622_419_200);
+        assert_eq!(
+            first.address.to_string(),
+            "RKxTdfmtxtfLDKZBgx6SvNkBtNu9jRYnLh"
+        );
+        assert_eq!(dump.keys[1].kind, KeyKind::Reserve);
+        assert_eq!(dump.keys[1].birth_time, 1);
+    }
+
+    #[test]
+    fn birth_heights() {
+        // a block every minute from 1_600_000_000 on
+        let chain = MockChain::new(1000);
+        let client = MockRpc::new(move |cmd, args| {
+            chain.handle(cmd, args).unwrap_or_else(|| unexpected(cmd))
+        });
+        let time = |height: u64| 1_600_000_000 + height * 60;
+
+        assert_eq!(birth_height(&client, 0).unwrap(), 0);
+        assert_eq!(
+            birth_height(&client, time(500) + TIMESTAMP_WINDOW).unwrap(),
+            500
+        );
+        assert_eq!(
+            birth_height(&client, time(500) + TIMESTAMP_WINDOW + 1).unwrap(),
+            501
+        );
+        assert_eq!(birth_height(&client, time(5000)).unwrap(), 999);
+
+        let dump = WalletDump::parse(DUMP).unwrap();
+        assert_eq!(dump.keys[1].birth_height(&client).unwrap(), 0);
+    }
+
+    #[test]
+    fn reject_invalid_lines() {
+        assert!(WalletDump::parse("Up1YVLk7 2021-05-31T00:00:00Z").is_err());
+        assert!(WalletDump::parse("Up1YVLk7 yesterday # addr=R").is_err());
+    }
+}
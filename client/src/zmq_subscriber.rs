@@ -0,0 +1,417 @@
+//! Subscribing to the ZeroMQ notifications komodod publishes with `-zmqpub<topic>=<address>`.
+//!
+//! Every message carries a sequence number per topic. A jump in it means messages were missed,
+//! and a sequence number that starts over means the daemon restarted; both are reported as a
+//! [`Notification::Gap`]. [`Subscriber::catch_up`] then recovers the missed blocks or
+//! transactions over RPC. Notifications can be delivered more than once around
+//! a gap.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use crate::bitcoin::hashes::hex::ToHex;
+use crate::bitcoin::hashes::{sha256d, Hash};
+use crate::bitcoin::{BlockHash, Txid};
+use crate::json::DecodeRawTransactionResult;
+use crate::{Error, Result, RpcApi};
+
+// version, previous block, merkle root, final sapling root, time, bits, nonce
+const BLOCK_HEADER_SIZE: usize = 4 + 32 + 32 + 32 + 4 + 4 + 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Topic {
+    HashBlock,
+    HashTx,
+    RawBlock,
+    RawTx,
+}
+
+impl Topic {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Topic::HashBlock => "hashblock",
+            Topic::HashTx => "hashtx",
+            Topic::RawBlock => "rawblock",
+            Topic::RawTx => "rawtx",
+        }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            b"hashblock" => Some(Topic::HashBlock),
+            b"hashtx" => Some(Topic::HashTx),
+            b"rawblock" => Some(Topic::RawBlock),
+            b"rawtx" => Some(Topic::RawTx),
+            _ => None,
+        }
+    }
+
+    fn is_block(&self) -> bool {
+        *self == Topic::HashBlock || *self == Topic::RawBlock
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RawBlock {
+    pub hash: BlockHash,
+    pub previous_blockhash: BlockHash,
+    pub bytes: Vec<u8>,
+}
+
+impl RawBlock {
+    pub fn decode(bytes: Vec<u8>) -> Option<Self> {
+        let solution_len = read_compact_size(bytes.get(BLOCK_HEADER_SIZE..)?)?;
+        let header_len = BLOCK_HEADER_SIZE + solution_len.0 + solution_len.1 as usize;
+        let header = bytes.get(..header_len)?;
+
+        Some(RawBlock {
+            hash: BlockHash::from_hash(sha256d::Hash::hash(header)),
+            previous_blockhash: BlockHash::from_slice(&bytes[4..36]).ok()?,
+            bytes,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RawTransaction {
+    pub txid: Txid,
+    pub bytes: Vec<u8>,
+}
+
+impl RawTransaction {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        RawTransaction {
+            txid: Txid::from_hash(sha256d::Hash::hash(&bytes)),
+            bytes,
+        }
+    }
+
+    /// Decodes the transaction through the daemon.
+    pub fn decode<C: RpcApi>(&self, client: &C) -> Result<DecodeRawTransactionResult> {
+        client.decoderawtransaction(&self.bytes.to_hex())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Notification {
+    HashBlock(BlockHash),
+    HashTx(Txid),
+    RawBlock(RawBlock),
+    RawTx(RawTransaction),
+    /// `missed` messages of `topic` were not received, an unknown number if the daemon
+    /// restarted.
+    Gap {
+        topic: Topic,
+        missed: Option<u32>,
+    },
+}
+
+pub struct Subscriber {
+    // the socket has to be dropped before its context
+    socket: zmq::Socket,
+    _context: zmq::Context,
+    sequences: HashMap<Topic, u32>,
+    last_block: Option<BlockHash>,
+    pending: VecDeque<Notification>,
+}
+
+impl Subscriber {
+    /// Connects to a komodod ZMQ endpoint, e.g. `tcp://127.0.0.1:28332`, and subscribes to
+    /// `topics`.
+    pub fn connect(endpoint: &str, topics: &[Topic]) -> Result<Self> {
+        let context = zmq::Context::new();
+        let socket = context.socket(zmq::SUB)?;
+        socket.connect(endpoint)?;
+        for topic in topics {
+            socket.set_subscribe(topic.as_str().as_bytes())?;
+        }
+
+        Ok(Subscriber {
+            socket,
+            _context: context,
+            sequences: HashMap::new(),
+            last_block: None,
+            pending: VecDeque::new(),
+        })
+    }
+
+    /// Makes [`recv`](Subscriber::recv) give up after `timeout`; it waits forever otherwise.
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        let millis = timeout.map_or(-1, |t| t.as_millis() as i32);
+        self.socket.set_rcvtimeo(millis)?;
+        Ok(())
+    }
+
+    /// The last block that was announced or caught up with.
+    pub fn last_block(&self) -> Option<BlockHash> {
+        self.last_block
+    }
+
+    /// Blocks until the next notification.
+    pub fn recv(&mut self) -> Result<Notification> {
+        if let Some(notification) = self.pending.pop_front() {
+            return Ok(self.delivered(notification));
+        }
+
+        loop {
+            let parts = self.socket.recv_multipart(0)?;
+            if parts.len() < 2 {
+                continue;
+            }
+            let topic = match Topic::from_bytes(&parts[0]) {
+                Some(topic) => topic,
+                None => continue,
+            };
+
+            let notification = self.decode(topic, parts[1].clone())?;
+
+            // older daemons don't send sequence numbers
+            if let Some(sequence) = parts.get(2).and_then(|s| read_sequence(s)) {
+                if let Some(previous) = self.sequences.insert(topic, sequence) {
+                    let missed = if sequence == previous.wrapping_add(1) {
+                        Some(0)
+                    } else if sequence > previous {
+                        Some(sequence - previous - 1)
+                    } else {
+                        // the daemon restarted and counts from 0 again
+                        None
+                    };
+                    if missed != Some(0) {
+                        // delivered after the gap, so a catch up starts at the last block
+                        // before it
+                        self.pending.push_back(notification);
+                        return Ok(Notification::Gap { topic, missed });
+                    }
+                }
+            }
+
+            return Ok(self.delivered(notification));
+        }
+    }
+
+    fn delivered(&mut self, notification: Notification) -> Notification {
+        match notification {
+            Notification::HashBlock(hash) => self.last_block = Some(hash),
+            Notification::RawBlock(ref block) => self.last_block = Some(block.hash),
+            _ => {}
+        }
+        notification
+    }
+
+    /// Like [`recv`](Subscriber::recv), but a gap is followed by what [`catch_up`] recovered.
+    ///
+    /// [`catch_up`]: Subscriber::catch_up
+    pub fn recv_with_catch_up<C: RpcApi>(&mut self, client: &C) -> Result<Vec<Notification>> {
+        let notification = self.recv()?;
+        match notification {
+            Notification::Gap { topic, .. } => {
+                let mut notifications = vec![notification];
+                notifications.extend(self.catch_up(client, topic)?);
+                Ok(notifications)
+            }
+            notification => Ok(vec![notification]),
+        }
+    }
+
+    /// Recovers what might have been missed of `topic`. For blocks these are the hashes of the
+    /// blocks after the last received block that is still in the active chain, so blocks that
+    /// replaced it are included; for transactions the hashes of all transactions in the mempool.
+    pub fn catch_up<C: RpcApi>(&mut self, client: &C, topic: Topic) -> Result<Vec<Notification>> {
+        if !topic.is_block() {
+            return Ok(client
+                .get_raw_mempool()?
+                .into_iter()
+                .map(Notification::HashTx)
+                .collect());
+        }
+
+        let tip = client.get_block_count()? as u64;
+        let from = match self.last_block {
+            Some(hash) => fork_point(client, &hash, tip)? + 1,
+            // nothing to catch up with before the first block
+            None => tip,
+        };
+
+        let mut notifications = vec![];
+        for height in from..=tip {
+            let hash = client.get_block_hash(height)?;
+            notifications.push(Notification::HashBlock(hash));
+            self.last_block = Some(hash);
+        }
+        Ok(notifications)
+    }
+
+    fn decode(&self, topic: Topic, body: Vec<u8>) -> Result<Notification> {
+        let len = body.len();
+        let invalid = || {
+            Error::KMDError(format!(
+                "invalid {} notification of {} bytes",
+                topic.as_str(),
+                len
+            ))
+        };
+
+        match topic {
+            Topic::HashBlock | Topic::HashTx => {
+                if len != 32 {
+                    return Err(invalid());
+                }
+                // hashes are sent in the byte order they are displayed in
+                let mut hash = [0u8; 32];
+                for (i, byte) in body.iter().rev().enumerate() {
+                    hash[i] = *byte;
+                }
+                Ok(if topic == Topic::HashBlock {
+                    Notification::HashBlock(BlockHash::from_inner(hash))
+                } else {
+                    Notification::HashTx(Txid::from_inner(hash))
+                })
+            }
+            Topic::RawBlock => RawBlock::decode(body)
+                .map(Notification::RawBlock)
+                .ok_or_else(invalid),
+            Topic::RawTx => Ok(Notification::RawTx(RawTransaction::new(body))),
+        }
+    }
+}
+
+// The height of the last block of the chain ending in `hash` that is in the active chain.
+fn fork_point<C: RpcApi>(client: &C, hash: &BlockHash, tip: u64) -> Result<u64> {
+    let mut block = client.get_block(hash)?;
+    loop {
+        let height = block.height as u64;
+        if height <= tip && client.get_block_hash(height)? == block.hash {
+            return Ok(height);
+        }
+        block = match block.previous_blockhash {
+            Some(previous) => client.get_block(&previous)?,
+            None => {
+                return Err(Error::KMDError(String::from(
+                    "the genesis block left the active chain",
+                )))
+            }
+        };
+    }
+}
+
+fn read_sequence(bytes: &[u8]) -> Option<u32> {
+    if bytes.len() != 4 {
+        return None;
+    }
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// Returns the size of the compact size itself and the value it encodes.
+fn read_compact_size(bytes: &[u8]) -> Option<(usize, u64)> {
+    let first = *bytes.first()?;
+    let read = |n: usize| {
+        let mut buf = [0u8; 8];
+        buf[..n].copy_from_slice(bytes.get(1..=n)?);
+        Some((n + 1, u64::from_le_bytes(buf)))
+    };
+
+    match first {
+        0xfd => read(2),
+        0xfe => read(4),
+        0xff => read(8),
+        n => Some((1, n as u64)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{unexpected, MockChain, MockRpc};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::thread;
+
+    #[test]
+    fn detects_gaps() {
+        let context = zmq::Context::new();
+        let publisher = context.socket(zmq::PUB).unwrap();
+        publisher.bind("tcp://127.0.0.1:*").unwrap();
+        let endpoint = publisher.get_last_endpoint().unwrap().unwrap();
+
+        let mut subscriber = Subscriber::connect(&endpoint, &[Topic::HashBlock]).unwrap();
+        subscriber
+            .set_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        // subscriptions take a moment to reach the publisher
+        thread::sleep(Duration::from_millis(200));
+
+        let mut hash = [0u8; 32];
+        hash[0] = 0xab;
+        // the daemon restarts after the third message
+        for sequence in &[0u32, 1, 4, 0] {
+            publisher
+                .send_multipart(
+                    vec![&b"hashblock"[..], &hash[..], &sequence.to_le_bytes()[..]],
+                    0,
+                )
+                .unwrap();
+        }
+        // not subscribed
+        publisher
+            .send_multipart(vec![&b"hashtx"[..], &hash[..], &[0u8; 4][..]], 0)
+            .unwrap();
+
+        let mut expected_hash = hash;
+        expected_hash.reverse();
+        let expected = Notification::HashBlock(BlockHash::from_inner(expected_hash));
+
+        assert_eq!(subscriber.recv().unwrap(), expected);
+        assert_eq!(subscriber.recv().unwrap(), expected);
+        assert_eq!(
+            subscriber.recv().unwrap(),
+            Notification::Gap {
+                topic: Topic::HashBlock,
+                missed: Some(2)
+            }
+        );
+        assert_eq!(subscriber.recv().unwrap(), expected);
+        assert_eq!(
+            subscriber.recv().unwrap(),
+            Notification::Gap {
+                topic: Topic::HashBlock,
+                missed: None
+            }
+        );
+        assert_eq!(subscriber.recv().unwrap(), expected);
+        assert_eq!(
+            subscriber.last_block(),
+            Some(BlockHash::from_inner(expected_hash))
+        );
+    }
+
+    #[test]
+    fn catches_up_from_the_fork_point() {
+        let chain = Rc::new(RefCell::new(MockChain::new(5)));
+        let client = {
+            let chain = Rc::clone(&chain);
+            MockRpc::new(move |cmd, args| {
+                chain
+                    .borrow()
+                    .handle(cmd, args)
+                    .unwrap_or_else(|| unexpected(cmd))
+            })
+        };
+        // connecting doesn't wait for the endpoint
+        let mut subscriber = Subscriber::connect("tcp://127.0.0.1:1", &[Topic::HashBlock]).unwrap();
+
+        // the last two announced blocks were replaced by three others
+        subscriber.last_block = Some(chain.borrow().tip());
+        chain.borrow_mut().disconnect(2);
+        let new: Vec<_> = (0..3).map(|_| chain.borrow_mut().push(&[])).collect();
+
+        let notifications = subscriber.catch_up(&client, Topic::HashBlock).unwrap();
+        let expected: Vec<_> = new.iter().copied().map(Notification::HashBlock).collect();
+        assert_eq!(notifications, expected);
+        assert_eq!(subscriber.last_block(), Some(new[2]));
+
+        assert!(subscriber
+            .catch_up(&client, Topic::HashBlock)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -21,20 +21,32 @@ use std::str::FromStr;
 use std::collections::HashMap;
 
 #[derive(Clone, Debug)]
-pub enum PubkeyOrAddress<'a> {
-    Address(&'a Address),
-    Pubkey(&'a str),
+pub enum PubkeyOrAddress {
+    Address(Address),
+    Pubkey(bitcoin::PublicKey),
 }
 
-impl<'a> serde::Serialize for PubkeyOrAddress<'a> {
+impl serde::Serialize for PubkeyOrAddress {
     fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
         match *self {
-            PubkeyOrAddress::Address(a) => serde::Serialize::serialize(a, serializer),
-            PubkeyOrAddress::Pubkey(p) => serde::Serialize::serialize(p, serializer),
+            PubkeyOrAddress::Address(ref a) => serde::Serialize::serialize(a, serializer),
+            PubkeyOrAddress::Pubkey(ref p) => serializer.serialize_str(&p.to_string()),
         }
     }
 }
 
+impl From<Address> for PubkeyOrAddress {
+    fn from(address: Address) -> Self {
+        PubkeyOrAddress::Address(address)
+    }
+}
+
+impl From<bitcoin::PublicKey> for PubkeyOrAddress {
+    fn from(pubkey: bitcoin::PublicKey) -> Self {
+        PubkeyOrAddress::Pubkey(pubkey)
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct CoinSupply {
     pub result: String,
@@ -231,10 +243,12 @@ pub struct ListSinceBlockTransactions {
     pub vout: u16,
     #[serde(with = "komodo::util::amount::serde::as_kmd::opt", default)]
     pub fee: Option<SignedAmount>,
-    pub confirmations: u32,
-    pub blockhash: BlockHash,
-    pub blockindex: u32,
-    pub blocktime: u64,
+    /// -1 for transactions that conflict with one in the chain.
+    pub confirmations: i32,
+    pub rawconfirmations: Option<i32>,
+    pub blockhash: Option<BlockHash>,
+    pub blockindex: Option<u32>,
+    pub blocktime: Option<u64>,
     pub txid: Txid,
     pub time: u64,
     pub timereceived: u64,
@@ -285,6 +299,9 @@ pub struct ListUnspentResult {
     #[serde(rename = "redeemScript")]
     pub redeem_script: Option<Script>,
     pub spendable: bool,
+    // only returned by the KMD chain
+    #[serde(with = "komodo::util::amount::serde::as_kmd::opt", default)]
+    pub interest: Option<Amount>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -307,14 +324,24 @@ pub struct GetRawTransactionResultVerbose {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GetRawTransactionVin {
+    /// Hex of the coinbase script. Coinbase inputs have no `txid`, `vout` or `scriptSig`.
+    pub coinbase: Option<String>,
+    #[serde(default)]
     pub txid: bitcoin::Txid,
+    #[serde(default)]
     pub vout: u32,
-    #[serde(rename = "scriptSig")]
+    #[serde(rename = "scriptSig", default)]
     pub script_sig: GetRawTransactionVinScriptSig,
     pub sequence: u32,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+impl GetRawTransactionVin {
+    pub fn is_coinbase(&self) -> bool {
+        self.coinbase.is_some()
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct GetRawTransactionVinScriptSig {
     pub asm: String,
     pub hex: String,
@@ -478,6 +505,42 @@ pub struct ChainTxStats {
     pub txrate: f64,
 }
 
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct BlockHashesOptions {
+    #[serde(rename = "noOrphans")]
+    pub no_orphans: bool,
+    #[serde(rename = "logicalTimes")]
+    pub logical_times: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BlockHashLogicalTime {
+    pub blockhash: BlockHash,
+    pub logicalts: u64,
+}
+
+/// Number of segids on a staking chain.
+pub const SEGID_COUNT: u8 = 64;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LastSegidStakes {
+    #[serde(rename = "NotSet")]
+    pub not_set: u32,
+    #[serde(rename = "PoW")]
+    pub pow: u32,
+    #[serde(rename = "PoSPerc")]
+    pub pos_percentage: u32,
+    /// Staked blocks per segid, keyed by segid `0..SEGID_COUNT`.
+    #[serde(rename = "SegIds")]
+    pub segids: std::collections::BTreeMap<u8, u32>,
+}
+
+impl LastSegidStakes {
+    pub fn stakes(&self, segid: u8) -> u32 {
+        self.segids.get(&segid).copied().unwrap_or(0)
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct MempoolInfo {
     pub size: u32,
@@ -486,23 +549,136 @@ pub struct MempoolInfo {
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct RawMempool(HashMap<String, RawMempoolTransactionInfo>);
+pub struct RawMempool(HashMap<Txid, RawMempoolTransactionInfo>);
+
+impl RawMempool {
+    pub fn get(&self, txid: &Txid) -> Option<&RawMempoolTransactionInfo> {
+        self.0.get(txid)
+    }
+
+    pub fn contains(&self, txid: &Txid) -> bool {
+        self.0.contains_key(txid)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Txid, &RawMempoolTransactionInfo)> {
+        self.0.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn into_inner(self) -> HashMap<Txid, RawMempoolTransactionInfo> {
+        self.0
+    }
+}
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct RawMempoolTransactionInfo {
     pub size: u32,
-    pub fee: f32,
+    #[serde(with = "komodo::util::amount::serde::as_kmd")]
+    pub fee: Amount,
     pub time: u32,
     pub height: u32,
     pub startingpriority: f64,
     pub currentpriority: f64,
-    pub depends: Vec<String>, // this either returns an empty array or an array with txids
+    pub depends: Vec<Txid>, // unconfirmed transactions this one spends from
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct SpentInfoResult {
     pub txid: bitcoin::Txid,
     pub index: u32,
+    pub height: Option<u32>,
+}
+
+/// Argument for the address index RPCs. Requires `-addressindex`.
+///
+/// The height range is inclusive and only applied by the daemon when both ends are set.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct AddressIndexQuery {
+    pub addresses: Vec<Address>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end: Option<u32>,
+}
+
+impl AddressIndexQuery {
+    pub fn new(addresses: Vec<Address>) -> Self {
+        AddressIndexQuery {
+            addresses,
+            start: None,
+            end: None,
+        }
+    }
+
+    pub fn heights(mut self, start: u32, end: u32) -> Self {
+        self.start = Some(start);
+        self.end = Some(end);
+        self
+    }
+}
+
+impl From<Address> for AddressIndexQuery {
+    fn from(address: Address) -> Self {
+        AddressIndexQuery::new(vec![address])
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AddressBalance {
+    #[serde(with = "komodo::util::amount::serde::as_sat")]
+    pub balance: Amount,
+    #[serde(with = "komodo::util::amount::serde::as_sat")]
+    pub received: Amount,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AddressUtxo {
+    pub address: Address,
+    pub txid: Txid,
+    #[serde(rename = "outputIndex")]
+    pub output_index: u32,
+    pub script: Script,
+    #[serde(with = "komodo::util::amount::serde::as_sat")]
+    pub satoshis: Amount,
+    pub height: u32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AddressUtxosWithChainInfo {
+    pub utxos: Vec<AddressUtxo>,
+    pub hash: BlockHash,
+    pub height: u32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AddressDelta {
+    #[serde(with = "komodo::util::amount::serde::as_sat")]
+    pub satoshis: SignedAmount,
+    pub txid: Txid,
+    pub index: u32,
+    pub blockindex: u32,
+    pub height: u32,
+    pub address: Address,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AddressMempoolEntry {
+    pub address: Address,
+    pub txid: Txid,
+    pub index: u32,
+    #[serde(with = "komodo::util::amount::serde::as_sat")]
+    pub satoshis: SignedAmount,
+    pub timestamp: u64,
+    // only for spends
+    pub prevtxid: Option<Txid>,
+    pub prevout: Option<u32>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -539,6 +715,52 @@ pub struct TxOutSetInfoResult {
     pub total_amount: f64,
 }
 
+/// Set on keys written with a passphrase; only the owner can update them.
+pub const KV_PROTECTED: u32 = 1;
+/// Set on keys holding binary values.
+pub const KV_BINARY: u32 = 2;
+
+/// A key stored on a KV-enabled asset chain, as returned by `kvsearch`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct KvRecord {
+    pub coin: String,
+    pub currentheight: u32,
+    pub key: String,
+    pub keylen: u32,
+    /// Hash of the pubkey derived from the update passphrase, all zeros for unprotected keys.
+    pub owner: String,
+    pub height: u32,
+    pub expiration: u32,
+    pub flags: u32,
+    pub value: String,
+    pub valuesize: u32,
+}
+
+impl KvRecord {
+    pub fn is_protected(&self) -> bool {
+        self.flags & KV_PROTECTED != 0
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.currentheight >= self.expiration
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct KvUpdateResult {
+    pub coin: String,
+    pub height: u32,
+    pub expiration: u32,
+    pub flags: u32,
+    pub key: String,
+    pub keylen: u32,
+    pub value: String,
+    pub valuesize: u32,
+    #[serde(with = "komodo::util::amount::serde::as_kmd")]
+    pub fee: Amount,
+    pub txid: Txid,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct MinerIds {
     pub mined: Vec<MinerId>,
@@ -571,6 +793,269 @@ pub struct Notary {
     pub kmd_address: Address,
 }
 
+/// The data a notary OP_RETURN commits to.
+///
+/// Back notarizations (notarizations of KMD on an asset chain, or of BTC on KMD) also carry the
+/// txid of the notarization on the destination chain and, on asset chains, the MoMoM.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NotarizationData {
+    pub block_hash: BlockHash,
+    pub height: u32,
+    pub dest_txid: Option<Txid>,
+    /// Symbol of the chain being notarized.
+    pub symbol: String,
+    pub mom: Option<MerkleOfMerkles>,
+    pub momom: Option<MerkleOfMerkles>,
+}
+
+/// A merkle root over the merkle roots of a range of blocks, with the size of that range.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MerkleOfMerkles {
+    pub root: bitcoin::hashes::sha256d::Hash,
+    pub depth: u32,
+    /// Only set on MoM, 0 for the MoMoM.
+    pub ccid: u16,
+}
+
+const NOTARIZATION_MAX_SYMBOL: usize = 64;
+
+impl NotarizationData {
+    /// Decodes the OP_RETURN payload of a notarization. Whether it is a back notarization is
+    /// detected from the length of the data.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        NotarizationData::decode_as(data, false).or_else(|| NotarizationData::decode_as(data, true))
+    }
+
+    fn decode_as(data: &[u8], back: bool) -> Option<Self> {
+        use bitcoin::hashes::Hash;
+
+        let read_u32 = |bytes: &[u8]| {
+            let mut buf = [0u8; 4];
+            buf.copy_from_slice(bytes);
+            u32::from_le_bytes(buf)
+        };
+
+        let mut offset = 36;
+        if data.len() < offset {
+            return None;
+        }
+        let block_hash = BlockHash::from_slice(&data[..32]).ok()?;
+        let height = read_u32(&data[32..36]);
+
+        let dest_txid = if back {
+            let txid = Txid::from_slice(data.get(offset..offset + 32)?).ok()?;
+            offset += 32;
+            Some(txid)
+        } else {
+            None
+        };
+
+        let symbol_len = data[offset..]
+            .iter()
+            .take(NOTARIZATION_MAX_SYMBOL)
+            .position(|b| *b == 0)?;
+        let symbol = &data[offset..offset + symbol_len];
+        if symbol.is_empty() || !symbol.iter().all(|b| b.is_ascii_graphic()) {
+            return None;
+        }
+        let symbol = String::from_utf8(symbol.to_vec()).ok()?;
+        offset += symbol_len + 1;
+
+        let read_mom = |bytes: &[u8], ccid: bool| {
+            Some(MerkleOfMerkles {
+                root: bitcoin::hashes::sha256d::Hash::from_slice(&bytes[..32]).ok()?,
+                depth: read_u32(&bytes[32..36]),
+                ccid: if ccid {
+                    u16::from_le_bytes([bytes[36], bytes[37]])
+                } else {
+                    0
+                },
+            })
+        };
+
+        let rest = &data[offset..];
+        let (mom, momom) = match rest.len() {
+            0 => (None, None),
+            38 => (Some(read_mom(rest, true)?), None),
+            74 if back => (
+                Some(read_mom(rest, true)?),
+                Some(read_mom(&rest[38..], false)?),
+            ),
+            _ => return None,
+        };
+
+        Some(NotarizationData {
+            block_hash,
+            height,
+            dest_txid,
+            symbol,
+            mom,
+            momom,
+        })
+    }
+
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        NotarizationData::decode(&hex::decode(hex).ok()?)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        use bitcoin::hashes::Hash;
+
+        let mut data = Vec::with_capacity(150);
+        data.extend_from_slice(&self.block_hash.into_inner());
+        data.extend_from_slice(&self.height.to_le_bytes());
+        if let Some(txid) = self.dest_txid {
+            data.extend_from_slice(&txid.into_inner());
+        }
+        data.extend_from_slice(self.symbol.as_bytes());
+        data.push(0);
+        if let Some(mom) = self.mom {
+            data.extend_from_slice(&mom.root.into_inner());
+            data.extend_from_slice(&mom.depth.to_le_bytes());
+            data.extend_from_slice(&mom.ccid.to_le_bytes());
+            if let Some(momom) = self.momom {
+                data.extend_from_slice(&momom.root.into_inner());
+                data.extend_from_slice(&momom.depth.to_le_bytes());
+            }
+        }
+        data
+    }
+
+    pub fn is_back_notarization(&self) -> bool {
+        self.dest_txid.is_some()
+    }
+}
+
+impl Serialize for NotarizationData {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(self.encode()))
+    }
+}
+
+impl<'de> Deserialize<'de> for NotarizationData {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        NotarizationData::from_hex(&hex)
+            .ok_or_else(|| de::Error::custom(format!("invalid notarization data: {}", hex)))
+    }
+}
+
+/// The notarization fields of `getinfo`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NotarizationInfo {
+    pub name: String,
+    /// The last notarized height of this chain.
+    pub notarized: u32,
+    #[serde(rename = "prevMoMheight")]
+    pub prev_mom_height: u32,
+    pub notarizedhash: BlockHash,
+    /// The notarization transaction on the destination chain (KMD for asset chains, BTC for
+    /// KMD).
+    pub notarizedtxid: Txid,
+    /// Height of `notarizedtxid` on the destination chain, `None` while it is in its mempool.
+    #[serde(deserialize_with = "height_or_mempool", default)]
+    pub notarizedtxid_height: Option<u32>,
+    // only on asset chains
+    /// The last KMD height notarized on BTC.
+    #[serde(rename = "KMDnotarized_height")]
+    pub kmd_notarized_height: Option<u32>,
+    /// KMD confirmations of `notarizedtxid`, which don't make it BTC final.
+    pub notarized_confirms: Option<u32>,
+}
+
+impl NotarizationInfo {
+    pub fn is_kmd(&self) -> bool {
+        self.name == "KMD"
+    }
+
+    /// Whether the last notarization is secured by BTC: directly on KMD, and on asset chains
+    /// once the KMD block holding `notarizedtxid` is notarized on BTC.
+    pub fn is_btc_final(&self) -> bool {
+        if self.is_kmd() {
+            return true;
+        }
+        match (self.notarizedtxid_height, self.kmd_notarized_height) {
+            (Some(height), Some(kmd_notarized)) => height <= kmd_notarized,
+            _ => false,
+        }
+    }
+}
+
+fn height_or_mempool<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Number(n) => Ok(n.as_u64().map(|n| n as u32)),
+        _ => Ok(None),
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TxNotarizedConfirmedResult {
+    pub result: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct BlockNotarizations {
+    /// Notarizations by KMD notaries.
+    #[serde(rename = "KMD", default)]
+    pub kmd: Vec<BlockNotarization>,
+    /// Notarizations by LABS notaries.
+    #[serde(rename = "LABS", default)]
+    pub labs: Vec<BlockNotarization>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BlockNotarization {
+    pub txid: Txid,
+    /// Symbol of the chain being notarized.
+    pub chain: String,
+    pub height: u32,
+    pub blockhash: BlockHash,
+    /// Indexes of the signing notaries in the notary list of the block height.
+    pub notaries: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ScanNotarizationsResult {
+    /// Height of the block containing the notarization.
+    pub height: u32,
+    pub hash: Txid,
+    pub opreturn: NotarizationData,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HeightMoM {
+    pub coin: String,
+    pub height: u32,
+    pub timestamp: u64,
+    pub depth: u32,
+    pub notarized_height: u32,
+    #[serde(rename = "MoM")]
+    pub mom: bitcoin::hashes::sha256d::Hash,
+    pub kmdtxid: Txid,
+    // only on asset chains
+    #[serde(rename = "MoMoM")]
+    pub momom: Option<bitcoin::hashes::sha256d::Hash>,
+    #[serde(rename = "MoMoMoffset")]
+    pub momom_offset: Option<u32>,
+    #[serde(rename = "MoMoMdepth")]
+    pub momom_depth: Option<u32>,
+    pub kmdstarti: Option<u32>,
+    pub kmdendi: Option<u32>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CalcMoMResult {
+    pub coin: String,
+    pub height: u32,
+    #[serde(rename = "MoMdepth")]
+    pub mom_depth: u32,
+    #[serde(rename = "MoM")]
+    pub mom: bitcoin::hashes::sha256d::Hash,
+}
+
 // Used for createrawtransaction argument.
 #[derive(Serialize, Clone, PartialEq, Eq, Debug)]
 #[serde(rename_all = "camelCase")]
@@ -581,6 +1066,680 @@ pub struct CreateRawTransactionInput {
     pub sequence: Option<u32>,
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CreateMultiSigResult {
+    pub address: Address,
+    #[serde(rename = "redeemScript")]
+    pub redeem_script: Script,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DecodeRawTransactionResult {
+    pub txid: bitcoin::Txid,
+    pub overwintered: Option<bool>,
+    pub version: u32,
+    pub locktime: u64,
+    pub expiryheight: Option<u32>,
+    pub vin: Vec<GetRawTransactionVin>,
+    pub vout: Vec<GetRawTransactionVout>,
+    pub vjoinsplit: Vec<GetRawTransactionVJoinSplit>,
+}
+
+// Used for signrawtransaction argument.
+#[derive(Serialize, Clone, PartialEq, Eq, Debug)]
+pub struct SignRawTransactionInput {
+    pub txid: bitcoin::Txid,
+    pub vout: u32,
+    #[serde(rename = "scriptPubKey")]
+    pub script_pub_key: Script,
+    #[serde(rename = "redeemScript", skip_serializing_if = "Option::is_none")]
+    pub redeem_script: Option<Script>,
+    // Sapling signatures commit to the amount of the output that is spent.
+    #[serde(
+        with = "komodo::util::amount::serde::as_kmd::opt",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    pub amount: Option<Amount>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SigHashType {
+    #[serde(rename = "ALL")]
+    All,
+    #[serde(rename = "NONE")]
+    None,
+    #[serde(rename = "SINGLE")]
+    Single,
+    #[serde(rename = "ALL|ANYONECANPAY")]
+    AllPlusAnyoneCanPay,
+    #[serde(rename = "NONE|ANYONECANPAY")]
+    NonePlusAnyoneCanPay,
+    #[serde(rename = "SINGLE|ANYONECANPAY")]
+    SinglePlusAnyoneCanPay,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SignRawTransactionResult {
+    pub hex: String,
+    pub complete: bool,
+    #[serde(default)]
+    pub errors: Vec<SignRawTransactionResultError>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SignRawTransactionResultError {
+    pub txid: bitcoin::Txid,
+    pub vout: u32,
+    #[serde(rename = "scriptSig")]
+    pub script_sig: String,
+    pub sequence: u32,
+    pub error: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ClaimRewardsResult {
+    pub txid: bitcoin::Txid,
+    pub address: Address,
+    pub inputs: Vec<bitcoin::OutPoint>,
+    pub locktime: i64,
+    // sum of the spent UTXOs, without rewards
+    #[serde(with = "komodo::util::amount::serde::as_kmd")]
+    pub principal: Amount,
+    #[serde(with = "komodo::util::amount::serde::as_kmd")]
+    pub claimed: Amount,
+    #[serde(with = "komodo::util::amount::serde::as_kmd")]
+    pub fee: Amount,
+    // the amount that was sent back to `address`: principal + claimed - fee
+    #[serde(with = "komodo::util::amount::serde::as_kmd")]
+    pub sent: Amount,
+}
+
+/// The size of the memo field of a shielded output.
+pub const MEMO_SIZE: usize = 512;
+
+/// The memo of a shielded output, encoded as described in ZIP 302.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Memo {
+    /// 0xF6 followed by zeros.
+    Empty,
+    /// UTF-8 text, padded with zeros.
+    Text(String),
+    /// 0xFF followed by arbitrary data. The padding can't be told apart from the data, so a
+    /// decoded memo holds all 511 bytes.
+    Arbitrary(Vec<u8>),
+    /// A memo in a format that is reserved for future use.
+    Unknown(Vec<u8>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MemoError {
+    TooLong(usize),
+    InvalidHex,
+}
+
+impl Display for MemoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            MemoError::TooLong(len) => write!(
+                f,
+                "memo of {} bytes does not fit in {} bytes",
+                len, MEMO_SIZE
+            ),
+            MemoError::InvalidHex => write!(f, "memo is not valid hex"),
+        }
+    }
+}
+
+impl std::error::Error for MemoError {}
+
+impl Memo {
+    pub fn from_text(text: &str) -> Result<Self, MemoError> {
+        if text.len() > MEMO_SIZE {
+            return Err(MemoError::TooLong(text.len()));
+        }
+        if text.is_empty() {
+            return Ok(Memo::Empty);
+        }
+
+        Ok(Memo::Text(String::from(text)))
+    }
+
+    pub fn from_data(data: &[u8]) -> Result<Self, MemoError> {
+        // one byte is taken by the 0xFF marker
+        if data.len() > MEMO_SIZE - 1 {
+            return Err(MemoError::TooLong(data.len() + 1));
+        }
+
+        Ok(Memo::Arbitrary(data.to_vec()))
+    }
+
+    /// Decodes a memo as it is stored in a shielded output. Shorter memos are padded with
+    /// zeros.
+    pub fn decode(bytes: &[u8]) -> Result<Self, MemoError> {
+        if bytes.len() > MEMO_SIZE {
+            return Err(MemoError::TooLong(bytes.len()));
+        }
+
+        let mut memo = [0u8; MEMO_SIZE];
+        memo[..bytes.len()].copy_from_slice(bytes);
+
+        Ok(match memo[0] {
+            0xf6 if memo[1..].iter().all(|b| *b == 0) => Memo::Empty,
+            0xff => Memo::Arbitrary(memo[1..].to_vec()),
+            first if first <= 0xf4 => {
+                let end = memo.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+                match std::str::from_utf8(&memo[..end]) {
+                    Ok(text) if !text.is_empty() => Memo::Text(String::from(text)),
+                    _ => Memo::Unknown(memo.to_vec()),
+                }
+            }
+            _ => Memo::Unknown(memo.to_vec()),
+        })
+    }
+
+    pub fn from_hex(hex: &str) -> Result<Self, MemoError> {
+        let bytes = hex::decode(hex).map_err(|_| MemoError::InvalidHex)?;
+
+        Memo::decode(&bytes)
+    }
+
+    /// Checks that the memo fits in a shielded output, which the constructors already do.
+    pub fn validate(&self) -> Result<(), MemoError> {
+        let len = match *self {
+            Memo::Empty => 1,
+            Memo::Text(ref text) => text.len(),
+            Memo::Arbitrary(ref data) => data.len() + 1,
+            Memo::Unknown(ref bytes) => bytes.len(),
+        };
+        if len > MEMO_SIZE {
+            return Err(MemoError::TooLong(len));
+        }
+
+        Ok(())
+    }
+
+    /// The memo as it is stored in a shielded output.
+    pub fn encode(&self) -> Result<[u8; MEMO_SIZE], MemoError> {
+        self.validate()?;
+
+        let mut memo = [0u8; MEMO_SIZE];
+        match *self {
+            Memo::Empty => memo[0] = 0xf6,
+            Memo::Text(ref text) => memo[..text.len()].copy_from_slice(text.as_bytes()),
+            Memo::Arbitrary(ref data) => {
+                memo[0] = 0xff;
+                memo[1..=data.len()].copy_from_slice(data);
+            }
+            Memo::Unknown(ref bytes) => memo[..bytes.len()].copy_from_slice(bytes),
+        }
+
+        Ok(memo)
+    }
+
+    pub fn to_hex(&self) -> Result<String, MemoError> {
+        Ok(hex::encode(&self.encode()?[..]))
+    }
+
+    pub fn as_text(&self) -> Option<&str> {
+        match *self {
+            Memo::Text(ref text) => Some(text),
+            _ => None,
+        }
+    }
+}
+
+impl Serialize for Memo {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let hex = self.to_hex().map_err(ser::Error::custom)?;
+        serializer.serialize_str(&hex)
+    }
+}
+
+impl<'de> Deserialize<'de> for Memo {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        Memo::from_hex(&hex).map_err(de::Error::custom)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ZAddressType {
+    Sprout,
+    Sapling,
+}
+
+/// Whether the wallet is rescanned after a key is imported.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RescanPolicy {
+    Yes,
+    No,
+    #[serde(rename = "whenkeyisnew")]
+    WhenKeyIsNew,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ZTotalBalance {
+    #[serde(deserialize_with = "from_str")]
+    pub transparent: f64,
+    #[serde(deserialize_with = "from_str")]
+    pub private: f64,
+    #[serde(deserialize_with = "from_str")]
+    pub total: f64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ZListUnspentResult {
+    pub txid: Txid,
+    // Sapling notes have an outindex, Sprout notes a jsindex and jsoutindex.
+    pub outindex: Option<u32>,
+    pub jsindex: Option<u32>,
+    pub jsoutindex: Option<u32>,
+    pub confirmations: u32,
+    pub rawconfirmations: Option<u32>,
+    pub spendable: bool,
+    pub address: Address,
+    #[serde(with = "komodo::util::amount::serde::as_kmd")]
+    pub amount: Amount,
+    pub memo: Memo,
+    pub change: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ZReceivedByAddress {
+    pub txid: Txid,
+    #[serde(with = "komodo::util::amount::serde::as_kmd")]
+    pub amount: Amount,
+    pub memo: Memo,
+    pub outindex: Option<u32>,
+    pub jsindex: Option<u32>,
+    pub jsoutindex: Option<u32>,
+    pub confirmations: Option<u32>,
+    pub rawconfirmations: Option<u32>,
+    pub change: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ZViewTransaction {
+    pub txid: Txid,
+    pub spends: Vec<ZViewTransactionSpend>,
+    pub outputs: Vec<ZViewTransactionOutput>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ZViewTransactionSpend {
+    #[serde(rename = "type")]
+    pub pool: ZAddressType,
+    pub spend: Option<u32>,
+    #[serde(rename = "txidPrev")]
+    pub txid_prev: Txid,
+    #[serde(rename = "outputPrev")]
+    pub output_prev: Option<u32>,
+    pub address: Address,
+    #[serde(rename = "valueZat", with = "komodo::util::amount::serde::as_sat")]
+    pub value: Amount,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ZViewTransactionOutput {
+    #[serde(rename = "type")]
+    pub pool: ZAddressType,
+    pub output: Option<u32>,
+    pub address: Address,
+    // whether the output was sent to an address outside of the wallet
+    pub outgoing: bool,
+    #[serde(rename = "valueZat", with = "komodo::util::amount::serde::as_sat")]
+    pub value: Amount,
+    pub memo: Memo,
+    #[serde(rename = "memoStr")]
+    pub memo_str: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ZImportKeyResult {
+    #[serde(rename = "type")]
+    pub pool: ZAddressType,
+    pub address: Address,
+}
+
+/// The id of an asynchronous operation, returned by the z_ send calls.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct OperationId(pub String);
+
+impl Display for OperationId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// Used for z_sendmany argument.
+#[derive(Clone, Debug, Serialize)]
+pub struct ZSendManyRecipient {
+    pub address: Address,
+    #[serde(with = "komodo::util::amount::serde::as_kmd")]
+    pub amount: Amount,
+    // only for shielded recipients
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memo: Option<Memo>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OperationState {
+    Queued,
+    Executing,
+    Success,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OperationStatus {
+    pub id: OperationId,
+    pub status: OperationState,
+    pub creation_time: u64,
+    pub method: Option<String>,
+    pub params: Option<serde_json::Value>,
+    pub result: Option<OperationResult>,
+    pub error: Option<OperationError>,
+    pub execution_secs: Option<f64>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OperationResult {
+    pub txid: Txid,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OperationError {
+    pub code: i32,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ZShieldCoinbaseResult {
+    #[serde(rename = "remainingUTXOs")]
+    pub remaining_utxos: u32,
+    #[serde(rename = "remainingValue")]
+    pub remaining_value: f64,
+    #[serde(rename = "shieldingUTXOs")]
+    pub shielding_utxos: u32,
+    #[serde(rename = "shieldingValue")]
+    pub shielding_value: f64,
+    pub opid: OperationId,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ZMergeToAddressResult {
+    #[serde(rename = "remainingUTXOs")]
+    pub remaining_utxos: u32,
+    #[serde(rename = "remainingTransparentValue")]
+    pub remaining_transparent_value: f64,
+    #[serde(rename = "remainingNotes")]
+    pub remaining_notes: u32,
+    #[serde(rename = "remainingShieldedValue")]
+    pub remaining_shielded_value: f64,
+    #[serde(rename = "mergingUTXOs")]
+    pub merging_utxos: u32,
+    #[serde(rename = "mergingTransparentValue")]
+    pub merging_transparent_value: f64,
+    #[serde(rename = "mergingNotes")]
+    pub merging_notes: u32,
+    #[serde(rename = "mergingShieldedValue")]
+    pub merging_shielded_value: f64,
+    pub opid: OperationId,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MiningInfo {
+    pub blocks: u32,
+    pub currentblocksize: u64,
+    pub currentblocktx: u64,
+    pub difficulty: f64,
+    pub errors: String,
+    pub genproclimit: i32,
+    pub localsolps: f64,
+    pub networksolps: u64,
+    pub networkhashps: u64,
+    pub pooledtx: u64,
+    pub testnet: bool,
+    pub chain: String,
+    pub generate: Option<bool>,
+    pub staking: Option<bool>,
+    pub numthreads: Option<i32>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BlockSubsidy {
+    pub miner: f64,
+    pub founders: Option<f64>,
+}
+
+// Used for getblocktemplate argument.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct BlockTemplateRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub capabilities: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub longpollid: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BlockTemplate {
+    pub capabilities: Vec<String>,
+    pub version: u32,
+    pub previousblockhash: BlockHash,
+    pub finalsaplingroothash: Option<String>,
+    pub transactions: Vec<BlockTemplateTransaction>,
+    // the coinbase transaction, in the same format as `transactions`, plus the required flag
+    pub coinbasetxn: serde_json::Value,
+    pub longpollid: String,
+    pub target: String,
+    pub mintime: u64,
+    pub mutable: Vec<String>,
+    pub noncerange: String,
+    pub sigoplimit: u32,
+    pub sizelimit: u32,
+    pub curtime: u64,
+    pub bits: String,
+    pub height: u32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BlockTemplateTransaction {
+    pub data: String,
+    pub hash: Txid,
+    pub depends: Vec<u32>,
+    // in satoshis
+    pub fee: i64,
+    pub sigops: u32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PeerInfo {
+    pub id: u64,
+    pub addr: String,
+    pub addrlocal: Option<String>,
+    pub services: String,
+    pub lastsend: u64,
+    pub lastrecv: u64,
+    pub bytessent: u64,
+    pub bytesrecv: u64,
+    pub conntime: u64,
+    pub timeoffset: i64,
+    pub pingtime: Option<f64>,
+    pub pingwait: Option<f64>,
+    pub version: u32,
+    pub subver: String,
+    pub inbound: bool,
+    pub startingheight: i64,
+    // the node state fields are left out when the node has no state for the peer yet
+    pub banscore: Option<i32>,
+    pub synced_headers: Option<i64>,
+    pub synced_blocks: Option<i64>,
+    #[serde(default)]
+    pub inflight: Vec<u32>,
+    pub whitelisted: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NetworkInfo {
+    pub version: u32,
+    pub subversion: String,
+    pub protocolversion: u32,
+    pub localservices: String,
+    pub timeoffset: i64,
+    pub connections: u32,
+    pub networks: Vec<NetworkInfoNetwork>,
+    pub relayfee: f64,
+    pub localaddresses: Vec<NetworkInfoAddress>,
+    pub warnings: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NetworkInfoNetwork {
+    pub name: String,
+    pub limited: bool,
+    pub reachable: bool,
+    pub proxy: String,
+    pub proxy_randomize_credentials: Option<bool>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NetworkInfoAddress {
+    pub address: String,
+    pub port: u16,
+    pub score: i32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NetTotals {
+    pub totalbytesrecv: u64,
+    pub totalbytessent: u64,
+    pub timemillis: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddNodeCommand {
+    Add,
+    Remove,
+    OneTry,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SetBanCommand {
+    Add,
+    Remove,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AddedNodeInfo {
+    pub addednode: String,
+    // only with dns
+    pub connected: Option<bool>,
+    pub addresses: Option<Vec<AddedNodeAddress>>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AddedNodeAddress {
+    pub address: String,
+    // "inbound", "outbound" or "false"
+    pub connected: String,
+}
+
+/// An IP address with an optional netmask prefix length, e.g. `192.168.0.0/24`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SubNet {
+    pub ip: std::net::IpAddr,
+    pub prefix: Option<u8>,
+}
+
+impl SubNet {
+    pub fn new(ip: std::net::IpAddr, prefix: Option<u8>) -> Self {
+        SubNet { ip, prefix }
+    }
+}
+
+impl From<std::net::IpAddr> for SubNet {
+    fn from(ip: std::net::IpAddr) -> Self {
+        SubNet { ip, prefix: None }
+    }
+}
+
+impl Display for SubNet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.prefix {
+            Some(prefix) => write!(f, "{}/{}", self.ip, prefix),
+            None => write!(f, "{}", self.ip),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SubNetParseError(String);
+
+impl Display for SubNetParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid subnet: {}", self.0)
+    }
+}
+
+impl std::error::Error for SubNetParseError {}
+
+impl FromStr for SubNet {
+    type Err = SubNetParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || SubNetParseError(String::from(s));
+        let mut parts = s.splitn(2, '/');
+        let ip: std::net::IpAddr = parts.next().unwrap_or("").parse().map_err(|_| invalid())?;
+        let prefix = match parts.next() {
+            Some(prefix) => Some(prefix.parse::<u8>().map_err(|_| invalid())?),
+            None => None,
+        };
+
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        if prefix.map_or(false, |prefix| prefix > max) {
+            return Err(invalid());
+        }
+
+        Ok(SubNet { ip, prefix })
+    }
+}
+
+impl Serialize for SubNet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for SubNet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        from_str(deserializer)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct BannedSubNet {
+    pub address: SubNet,
+    pub banned_until: u64,
+    pub ban_created: u64,
+    pub ban_reason: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DeprecationInfo {
+    pub version: u32,
+    pub subversion: String,
+    pub deprecationheight: u32,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Snapshot {
     pub start_time: u64,
@@ -758,7 +1917,156 @@ where
 
 #[cfg(test)]
 mod tests {
-    use crate::TokelData;
+    use crate::{
+        LastSegidStakes, Memo, MemoError, MerkleOfMerkles, NotarizationData, PeerInfo, SubNet,
+        TokelData, MEMO_SIZE,
+    };
+    use bitcoin::{BlockHash, Txid};
+
+    #[test]
+    fn notarization_data() {
+        let mut data = NotarizationData {
+            block_hash: BlockHash::default(),
+            height: 2_000_000,
+            dest_txid: None,
+            symbol: String::from("DOC"),
+            mom: Some(MerkleOfMerkles {
+                root: Default::default(),
+                depth: 10,
+                ccid: 2,
+            }),
+            momom: None,
+        };
+        assert_eq!(data.encode().len(), 36 + 4 + 38);
+        assert_eq!(NotarizationData::decode(&data.encode()), Some(data.clone()));
+
+        data.dest_txid = Some(Txid::default());
+        data.momom = Some(MerkleOfMerkles {
+            root: Default::default(),
+            depth: 40,
+            ccid: 0,
+        });
+        assert_eq!(NotarizationData::decode(&data.encode()), Some(data.clone()));
+
+        // KMD notarization on BTC, without MoM
+        data.dest_txid = None;
+        data.mom = None;
+        data.momom = None;
+        data.symbol = String::from("KMD");
+        assert_eq!(
+            NotarizationData::from_hex(&hex::encode(data.encode())),
+            Some(data)
+        );
+
+        assert_eq!(NotarizationData::decode(&[0u8; 36]), None);
+    }
+
+    #[test]
+    fn last_segid_stakes() {
+        let json = r#"{"NotSet":0,"PoW":2,"PoSPerc":98,"SegIds":{"0":3,"1":0,"63":5}}"#;
+        let stakes: LastSegidStakes = serde_json::from_str(json).unwrap();
+        assert_eq!(stakes.pos_percentage, 98);
+        assert_eq!(stakes.stakes(63), 5);
+        assert_eq!(stakes.stakes(2), 0);
+    }
+
+    #[test]
+    fn peer_info() {
+        let peers: Vec<PeerInfo> = serde_json::from_str(
+            r#"[
+                {
+                    "id": 1,
+                    "addr": "185.25.48.236:7770",
+                    "addrlocal": "10.0.0.2:54321",
+                    "services": "0000000070000005",
+                    "lastsend": 1617710470,
+                    "lastrecv": 1617710471,
+                    "bytessent": 125412,
+                    "bytesrecv": 2215478,
+                    "conntime": 1617700000,
+                    "timeoffset": 0,
+                    "pingtime": 0.154,
+                    "version": 170010,
+                    "subver": "/MagicBean:2.0.15/",
+                    "inbound": false,
+                    "startingheight": 2310000,
+                    "banscore": 0,
+                    "synced_headers": 2310500,
+                    "synced_blocks": 2310500,
+                    "inflight": [2310501],
+                    "whitelisted": false
+                },
+                {
+                    "id": 2,
+                    "addr": "78.47.196.146:7770",
+                    "services": "0000000070000005",
+                    "lastsend": 0,
+                    "lastrecv": 0,
+                    "bytessent": 0,
+                    "bytesrecv": 0,
+                    "conntime": 1617710470,
+                    "timeoffset": 0,
+                    "pingwait": 1.2,
+                    "version": 0,
+                    "subver": "",
+                    "inbound": true,
+                    "startingheight": -1,
+                    "whitelisted": false
+                }
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(peers[0].synced_blocks, Some(2_310_500));
+        assert_eq!(peers[0].inflight, vec![2_310_501]);
+        assert_eq!(peers[1].banscore, None);
+        assert!(peers[1].inflight.is_empty());
+    }
+
+    #[test]
+    fn subnet_parsing() {
+        let subnet: SubNet = "192.168.0.0/24".parse().unwrap();
+        assert_eq!(subnet.prefix, Some(24));
+        assert_eq!(subnet.to_string(), "192.168.0.0/24");
+
+        let single: SubNet = "::1".parse().unwrap();
+        assert_eq!(single.prefix, None);
+
+        assert!("10.0.0.1/33".parse::<SubNet>().is_err());
+        assert!("example.com".parse::<SubNet>().is_err());
+    }
+
+    #[test]
+    fn memo_encoding() {
+        let text = Memo::from_text("payment 42").unwrap();
+        let encoded = text.encode().unwrap();
+        assert_eq!(&encoded[..10], b"payment 42");
+        assert!(encoded[10..].iter().all(|b| *b == 0));
+        assert_eq!(Memo::decode(&encoded).unwrap(), text);
+
+        let mut empty = vec![0xf6];
+        empty.resize(MEMO_SIZE, 0);
+        assert_eq!(Memo::decode(&empty).unwrap(), Memo::Empty);
+        assert_eq!(
+            Memo::from_text("").unwrap().encode().unwrap().to_vec(),
+            empty
+        );
+
+        let data = Memo::from_data(&[0xde, 0xad]).unwrap();
+        match Memo::from_hex(&data.to_hex().unwrap()).unwrap() {
+            Memo::Arbitrary(bytes) => assert_eq!(&bytes[..2], &[0xde, 0xad]),
+            memo => panic!("decoded as {:?}", memo),
+        }
+
+        let too_long = "a".repeat(MEMO_SIZE + 1);
+        assert_eq!(
+            Memo::from_text(&too_long),
+            Err(MemoError::TooLong(MEMO_SIZE + 1))
+        );
+        assert!(Memo::from_data(&[0u8; MEMO_SIZE]).is_err());
+        assert!(Memo::Text(too_long).encode().is_err());
+    }
+
     #[test]
     fn non_utf8_arbitrary_data() {
         let tokel_data = TokelData::from_data_string(